@@ -0,0 +1,262 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    auth::{bearer_token, constant_time_eq},
+    constants::{CACHE_UPDATE_LOCK, CANTEEN_EVENTS, CANTEEN_MAP, CANTEEN_MAP_INV, CRON_PAUSED},
+    cronjobs::{update_cache, update_cache_for_days},
+    date_funcs::resolve_date,
+    db_operations::{delete_meals_db, list_webhooks_db, merge_canteens_db, rename_canteen_db},
+    error::{ApiError, ApiJson, ApiPath, ApiQuery},
+    stuwe_request_funcs::{build_date_string, load_canteen_maps},
+    types::{Canteen, CanteenEvent, CanteenMealDiff},
+};
+
+// bearer tokens allowed to use the admin API, configured as comma separated ADMIN_API_TOKENS.
+// without tokens every admin request is rejected
+#[derive(Clone)]
+pub struct AdminTokens(Arc<Vec<String>>);
+
+impl AdminTokens {
+    pub fn from_env() -> Self {
+        let tokens: Vec<String> = std::env::var("ADMIN_API_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .collect();
+
+        if tokens.is_empty() {
+            log::info!("ADMIN_API_TOKENS is not set, the admin API is disabled");
+        }
+        AdminTokens(Arc::new(tokens))
+    }
+}
+
+impl AdminTokens {
+    pub fn authorize(&self, headers: &HeaderMap) -> bool {
+        bearer_token(headers).is_some_and(|token| {
+            self.0
+                .iter()
+                .any(|allowed| constant_time_eq(allowed.as_bytes(), token.as_bytes()))
+        })
+    }
+}
+
+pub async fn require_admin_token(
+    State(tokens): State<AdminTokens>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !tokens.authorize(req.headers()) {
+        return ApiError::Unauthorized.into_response();
+    }
+    next.run(req).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CacheUpdateQuery {
+    // comma separated list of dates (or aliases like 'tomorrow'), all upcoming weekdays if missing
+    pub dates: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CacheUpdateResponse {
+    // None: all upcoming weekdays
+    dates: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct CronResponse {
+    paused: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeletedResponse {
+    deleted: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RenameRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MergeRequest {
+    // ID of the canteen that is kept
+    pub into: u32,
+}
+
+// starts an update right away, its outcome shows up in /status
+pub async fn trigger_cache_update(
+    ApiQuery(query): ApiQuery<CacheUpdateQuery>,
+    today_updated_tx: broadcast::Sender<CanteenMealDiff>,
+) -> Result<Response, ApiError> {
+    let days = match query.dates.as_deref() {
+        None | Some("") => None,
+        Some(dates) => Some(
+            dates
+                .split(',')
+                .map(|date| resolve_date(date.trim(), None))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+    log::info!("Admin triggered cache update ({:?})", days);
+
+    let response = CacheUpdateResponse {
+        dates: days
+            .as_ref()
+            .map(|days| days.iter().map(|day| build_date_string(*day)).collect()),
+    };
+
+    tokio::spawn(async move {
+        let result = match days {
+            Some(days) => update_cache_for_days(&days, Some(today_updated_tx)).await,
+            None => update_cache(Some(today_updated_tx)).await,
+        };
+        if let Err(e) = result {
+            log::error!("Admin triggered cache update failed: {}", e);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(response)).into_response())
+}
+
+pub async fn get_cron() -> Json<CronResponse> {
+    Json(CronResponse {
+        paused: CRON_PAUSED.load(Ordering::Relaxed),
+    })
+}
+
+pub async fn pause_cron() -> Json<CronResponse> {
+    CRON_PAUSED.store(true, Ordering::Relaxed);
+    log::info!("Admin paused the cron job");
+    get_cron().await
+}
+
+pub async fn resume_cron() -> Json<CronResponse> {
+    CRON_PAUSED.store(false, Ordering::Relaxed);
+    log::info!("Admin resumed the cron job");
+    get_cron().await
+}
+
+// the next cache update fetches the deleted days again
+pub async fn clear_day(ApiPath(date): ApiPath<String>) -> Result<Json<DeletedResponse>, ApiError> {
+    let date = build_date_string(resolve_date(&date, None)?);
+    let _update_guard = CACHE_UPDATE_LOCK.write().await;
+    let deleted = delete_meals_db(None, &date)?;
+    log::info!("Admin cleared {} cached canteen days of {}", deleted, date);
+
+    Ok(Json(DeletedResponse { deleted }))
+}
+
+pub async fn clear_canteen_day(
+    ApiPath((canteen_id, date)): ApiPath<(u32, String)>,
+) -> Result<Json<DeletedResponse>, ApiError> {
+    ensure_canteen_exists(canteen_id)?;
+    let date = build_date_string(resolve_date(&date, Some(canteen_id))?);
+    let _update_guard = CACHE_UPDATE_LOCK.write().await;
+    let deleted = delete_meals_db(Some(canteen_id), &date)?;
+    log::info!(
+        "Admin cleared cached day {} of canteen {}",
+        date,
+        canteen_id
+    );
+
+    Ok(Json(DeletedResponse { deleted }))
+}
+
+pub async fn rename_canteen(
+    ApiPath(canteen_id): ApiPath<u32>,
+    ApiJson(rename): ApiJson<RenameRequest>,
+) -> Result<Json<Canteen>, ApiError> {
+    ensure_canteen_exists(canteen_id)?;
+    let name = rename.name.trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::InvalidRequest(
+            "name must not be empty".to_string(),
+        ));
+    }
+    // aliases count too, the scraper couldn't tell the two canteens apart otherwise
+    if CANTEEN_MAP_INV
+        .read()
+        .unwrap()
+        .get(&name)
+        .is_some_and(|id| *id != canteen_id)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Another canteen is already named or known as '{}'",
+            name
+        )));
+    }
+
+    let _update_guard = CACHE_UPDATE_LOCK.write().await;
+    rename_canteen_db(canteen_id, &name)?;
+    load_canteen_maps().await?;
+    log::info!("Admin renamed canteen {} to '{}'", canteen_id, name);
+
+    let canteen = Canteen {
+        id: canteen_id,
+        name,
+    };
+    let _ = CANTEEN_EVENTS.send(CanteenEvent::Renamed {
+        canteen: canteen.clone(),
+    });
+    Ok(Json(canteen))
+}
+
+// folds a canteen into another one, e.g. after StuWe changed a canteen's ID
+pub async fn merge_canteen(
+    ApiPath(canteen_id): ApiPath<u32>,
+    ApiJson(merge): ApiJson<MergeRequest>,
+) -> Result<Json<Canteen>, ApiError> {
+    ensure_canteen_exists(canteen_id)?;
+    ensure_canteen_exists(merge.into)?;
+    if canteen_id == merge.into {
+        return Err(ApiError::InvalidRequest(
+            "A canteen can't be merged into itself".to_string(),
+        ));
+    }
+
+    let _update_guard = CACHE_UPDATE_LOCK.write().await;
+    merge_canteens_db(canteen_id, merge.into)?;
+    load_canteen_maps().await?;
+    log::info!("Admin merged canteen {} into {}", canteen_id, merge.into);
+    let _ = CANTEEN_EVENTS.send(CanteenEvent::Merged {
+        id: canteen_id,
+        into: merge.into,
+    });
+
+    let name = CANTEEN_MAP
+        .read()
+        .unwrap()
+        .get(&merge.into)
+        .cloned()
+        .ok_or(ApiError::CanteenNotFound)?;
+    Ok(Json(Canteen {
+        id: merge.into,
+        name,
+    }))
+}
+
+// all webhooks including disabled ones, without their secrets
+pub async fn list_webhooks() -> Result<Response, ApiError> {
+    Ok(Json(list_webhooks_db(false)?).into_response())
+}
+
+fn ensure_canteen_exists(canteen_id: u32) -> Result<(), ApiError> {
+    if CANTEEN_MAP.read().unwrap().contains_key(&canteen_id) {
+        Ok(())
+    } else {
+        Err(ApiError::CanteenNotFound)
+    }
+}
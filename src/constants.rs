@@ -1,13 +1,41 @@
-use crate::types::Canteen;
+use crate::types::{Canteen, CanteenEvent, ScrapeStatus};
+use chrono::NaiveDate;
+use chrono_tz::Tz;
 use std::{
     collections::BTreeMap,
-    sync::{LazyLock, OnceLock},
+    sync::{atomic::AtomicBool, LazyLock, OnceLock},
 };
 
+// all StuWe Leipzig canteens share this timezone, relative dates are resolved in it
+pub const CANTEEN_TZ: Tz = chrono_tz::Europe::Berlin;
+
+// maximum number of days (inclusive) returned by a single range query
+pub const MAX_DATE_RANGE_DAYS: i64 = 14;
+
 pub static CANTEEN_MAP: LazyLock<std::sync::RwLock<BTreeMap<u32, String>>> =
     LazyLock::new(|| std::sync::RwLock::new(BTreeMap::new()));
 pub static CANTEEN_MAP_INV: LazyLock<std::sync::RwLock<BTreeMap<String, u32>>> =
     LazyLock::new(|| std::sync::RwLock::new(BTreeMap::new()));
 
+// scrape outcomes of each date from today on, past dates are dropped by update_cache
+pub static SCRAPE_STATUS: LazyLock<std::sync::RwLock<BTreeMap<NaiveDate, ScrapeStatus>>> =
+    LazyLock::new(|| std::sync::RwLock::new(BTreeMap::new()));
+
+// added/renamed/merged canteens, for WebSocket clients of the typed protocol
+pub static CANTEEN_EVENTS: LazyLock<tokio::sync::broadcast::Sender<CanteenEvent>> =
+    LazyLock::new(|| tokio::sync::broadcast::channel(20).0);
+
+// set via the admin API, the cron job skips its runs while paused
+pub static CRON_PAUSED: AtomicBool = AtomicBool::new(false);
+
+// cache updates of the cron job and the admin API must not diff against the same stored meals at once,
+// nor against meals or canteens the admin API is deleting, renaming or merging,
+// snapshots (readers) must not see the stored meals of an update whose diffs aren't broadcasted yet
+pub static CACHE_UPDATE_LOCK: LazyLock<tokio::sync::RwLock<()>> =
+    LazyLock::new(|| tokio::sync::RwLock::new(()));
+
+// /ready fails once the last successful scrape is older than this (overridable via READY_MAX_AGE_SECS)
+pub const DEFAULT_READY_MAX_AGE_SECS: i64 = 900;
+
 pub static OPENMENSA_ALL_CANTEENS: OnceLock<Vec<Canteen>> = OnceLock::new();
 pub static OPENMENSA_LIVE_CANTEENS: OnceLock<Vec<Canteen>> = OnceLock::new();
@@ -1,12 +1,17 @@
+use std::sync::atomic::Ordering;
+
 use anyhow::Result;
-use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, Weekday};
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
 use tokio::{sync::broadcast, task::JoinSet};
 use tokio_cron_scheduler::{Job, JobScheduler};
 
 use crate::{
-    constants::{CANTEEN_MAP, CANTEEN_MAP_INV},
-    stuwe_request_funcs::{invert_map, parse_and_save_meals},
-    types::CanteenMealDiff,
+    constants::{CACHE_UPDATE_LOCK, CANTEEN_MAP_INV, CRON_PAUSED, SCRAPE_STATUS},
+    date_funcs::canteen_today,
+    event_log,
+    metrics::{BROADCAST_SENDS, CHANGED_CANTEENS_PER_RUN},
+    stuwe_request_funcs::{fetch_meals, load_canteen_maps, save_meals},
+    types::{CanteenMealDiff, HasChanges},
 };
 
 pub async fn start_canteen_cache_job(today_updated_tx: broadcast::Sender<CanteenMealDiff>) {
@@ -15,6 +20,10 @@ pub async fn start_canteen_cache_job(today_updated_tx: broadcast::Sender<Canteen
     let cache_job = Job::new_async("0 0/5 * * * *", move |_uuid, mut _l| {
         let today_updated_tx = today_updated_tx.clone(); // clone for async move
         Box::pin(async move {
+            if CRON_PAUSED.load(Ordering::Relaxed) {
+                log::info!("Cron job is paused, skipping cache update");
+                return;
+            }
             log::info!("Updating Canteens");
 
             if let Err(e) = update_cache(Some(today_updated_tx)).await {
@@ -31,47 +40,74 @@ pub async fn update_cache(
     today_updated_tx: Option<broadcast::Sender<CanteenMealDiff>>,
 ) -> Result<()> {
     // will be run periodically: requests all canteen plans for the next 7 days
-    // returns a vector of canteens whose 'today' plan was updated (here only used for dbg logging)
 
-    let today = chrono::Local::now();
+    let today = canteen_today();
     let mut days: Vec<NaiveDate> = Vec::new();
     for i in 0..7 {
-        let day: DateTime<FixedOffset> = (today + Duration::days(i)).into();
+        let day = today + Duration::days(i);
 
         if ![Weekday::Sat, Weekday::Sun].contains(&day.weekday()) {
-            days.push(day.date_naive());
+            days.push(day);
         }
     }
 
-    // add tasks to joinset to execute concurrently
-    let mut set = JoinSet::new();
-    let mut canteens_changed_today = Vec::new();
+    update_cache_for_days(&days, today_updated_tx).await
+}
 
-    let canteen_map_inv_before = CANTEEN_MAP_INV.read().unwrap().clone();
+// requests the canteen plans of the given days, the diffs of all changed plans are broadcasted
+pub async fn update_cache_for_days(
+    days: &[NaiveDate],
+    today_updated_tx: Option<broadcast::Sender<CanteenMealDiff>>,
+) -> Result<()> {
+    let today = canteen_today();
 
-    for day in &days {
-        set.spawn(parse_and_save_meals(*day));
+    // the downloads don't touch the stored meals, so they don't hold up snapshots and the admin API
+    let mut set = JoinSet::new();
+    let mut scraped_days = Vec::new();
+
+    for day in days {
+        let day = *day;
+        set.spawn(async move { (day, fetch_meals(day).await) });
     }
 
     while let Some(res) = set.join_next().await {
         match res? {
+            (day, Ok(scraped_canteens)) => scraped_days.push((day, scraped_canteens)),
+            (day, Err(e)) => record_scrape_error(day, e),
+        }
+    }
+
+    let _update_guard = CACHE_UPDATE_LOCK.write().await;
+    let mut changed_canteen_days = Vec::new();
+
+    let canteen_map_inv_before = CANTEEN_MAP_INV.read().unwrap().clone();
+
+    // dates before today are no longer scraped, so their status would only go stale
+    SCRAPE_STATUS
+        .write()
+        .unwrap()
+        .retain(|date, _| *date >= today);
+
+    for (day, scraped_canteens) in scraped_days {
+        match save_meals(day, scraped_canteens).await {
             Ok(mut changed_canteen_diffs) => {
-                canteens_changed_today.append(&mut changed_canteen_diffs);
-            }
-            Err(e) => {
-                log::warn!("Error in cache execution: {}", e);
+                changed_canteen_days.append(&mut changed_canteen_diffs);
             }
+            Err(e) => record_scrape_error(day, e),
         }
     }
 
-    let canteen_map_inv_now = CANTEEN_MAP_INV.read().unwrap();
-    if canteen_map_inv_before != *canteen_map_inv_now {
-        *CANTEEN_MAP.write().unwrap() = invert_map(&canteen_map_inv_now);
+    let canteens_added = canteen_map_inv_before != *CANTEEN_MAP_INV.read().unwrap();
+    if canteens_added {
+        load_canteen_maps().await?;
     }
 
     if let Some(tx) = today_updated_tx.as_ref() {
-        for canteen_diff in &canteens_changed_today {
-            match tx.send(canteen_diff.clone()) {
+        for canteen_diff in changed_canteen_days.iter().cloned() {
+            // logged first, so clients resuming right after the send can't miss it
+            let canteen_diff = event_log::record(canteen_diff);
+            BROADCAST_SENDS.inc();
+            match tx.send(canteen_diff) {
                 Ok(subs) => {
                     log::info!("Broadcasted canteen diff to {} subscribers", subs);
                 }
@@ -82,10 +118,22 @@ pub async fn update_cache(
         }
     }
 
-    log::info!(
-        "{} Canteens changed meals of current day",
-        canteens_changed_today.len()
-    );
+    let changed_meals = changed_canteen_days
+        .iter()
+        .filter(|diff| diff.has_changes())
+        .count();
+    CHANGED_CANTEENS_PER_RUN.observe(changed_meals as f64);
+    log::info!("{} canteen days changed meals", changed_meals);
 
     Ok(())
 }
+
+fn record_scrape_error(day: NaiveDate, e: anyhow::Error) {
+    log::warn!("Error in cache execution: {}", e);
+    SCRAPE_STATUS
+        .write()
+        .unwrap()
+        .entry(day)
+        .or_default()
+        .last_error = Some(format!("{:#}", e));
+}
@@ -0,0 +1,108 @@
+use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
+
+use crate::{
+    constants::CANTEEN_TZ, db_operations::get_next_open_day_db, error::ApiError,
+    stuwe_request_funcs::build_date_string,
+};
+
+pub const RESOLVED_DATE_HEADER: &str = "x-resolved-date";
+
+pub fn canteen_today() -> NaiveDate {
+    Utc::now().with_timezone(&CANTEEN_TZ).date_naive()
+}
+
+// aliases point to another date every day, so they must not be answered with the Last-Modified
+// of the resolved date: If-Modified-Since would keep a client on yesterday's menu
+pub fn is_date_alias(date: &str) -> bool {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err()
+}
+
+// resolves either an absolute date (%Y-%m-%d) or one of the aliases
+// 'today', 'tomorrow', a weekday name ('monday'/'mon', next occurrence incl. today) or 'next_open'.
+// 'next_open' is the first day from today on with stored meals,
+// for the given canteen or (if None) for any canteen
+pub fn resolve_date(date: &str, canteen_id: Option<u32>) -> Result<NaiveDate, ApiError> {
+    if let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Ok(date);
+    }
+
+    let today = canteen_today();
+    match date.to_lowercase().as_str() {
+        "today" => Ok(today),
+        "tomorrow" => Ok(today + Duration::days(1)),
+        "next_open" => {
+            let next_open = get_next_open_day_db(canteen_id, &build_date_string(today))?
+                .and_then(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok());
+
+            next_open.ok_or(ApiError::NoOpenDay)
+        }
+        other => match other.parse::<Weekday>() {
+            Ok(weekday) => Ok(next_weekday(today, weekday)),
+            Err(_) => Err(ApiError::InvalidDate),
+        },
+    }
+}
+
+// the next day with the given weekday, today if it already is that weekday
+fn next_weekday(today: NaiveDate, weekday: Weekday) -> NaiveDate {
+    let days_ahead =
+        (7 + weekday.num_days_from_monday() - today.weekday().num_days_from_monday()) % 7;
+    today + Duration::days(days_ahead.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn weekdays_wrap_around_to_the_next_week() {
+        // a wednesday
+        let today = date("2026-10-21");
+        assert_eq!(next_weekday(today, Weekday::Wed), today);
+        assert_eq!(next_weekday(today, Weekday::Fri), date("2026-10-23"));
+        assert_eq!(next_weekday(today, Weekday::Mon), date("2026-10-26"));
+        assert_eq!(next_weekday(today, Weekday::Tue), date("2026-10-27"));
+        // sunday to monday crosses the week and the month
+        assert_eq!(
+            next_weekday(date("2026-05-31"), Weekday::Mon),
+            date("2026-06-01")
+        );
+    }
+
+    #[test]
+    fn weekday_aliases_resolve_within_a_week() {
+        let today = canteen_today();
+        for alias in ["monday", "Mon", "SUNDAY", "fri"] {
+            let resolved = resolve_date(alias, None).unwrap();
+            assert!(resolved >= today && resolved < today + Duration::days(7));
+            assert_eq!(resolved.weekday(), alias.parse::<Weekday>().unwrap());
+        }
+    }
+
+    #[test]
+    fn absolute_dates_and_aliases() {
+        assert_eq!(
+            resolve_date("2026-10-19", None).unwrap(),
+            date("2026-10-19")
+        );
+        assert!(!is_date_alias("2026-10-19"));
+        assert!(is_date_alias("today"));
+
+        let tomorrow = resolve_date("Tomorrow", None).unwrap();
+        assert_eq!(
+            tomorrow - resolve_date("today", None).unwrap(),
+            Duration::days(1)
+        );
+
+        for invalid in ["2026-13-01", "yesterday", "", "mondays"] {
+            assert!(matches!(
+                resolve_date(invalid, None),
+                Err(ApiError::InvalidDate)
+            ));
+        }
+    }
+}
@@ -1,13 +1,24 @@
-use anyhow::Result;
-use chrono::NaiveDate;
-use rusqlite::{params, Connection};
-use std::collections::BTreeMap;
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::LazyLock,
+};
 
-use crate::{stuwe_request_funcs::build_date_string, types::MealGroup};
+use crate::{
+    error::{ApiError, ApiResult},
+    metrics::DB_OPERATION_DURATION,
+    rate_limit::env_or,
+    stuwe_request_funcs::build_date_string,
+    types::{MealGroup, NewWebhook, StoredDay, UpdateScope, Webhook},
+};
 
 const DB_FILENAME: &str = "meals.sqlite";
 
-pub fn check_or_create_db_tables() -> rusqlite::Result<()> {
+// newest revisions kept per canteen day (MEAL_REVISIONS_KEPT), older ones are deleted when a new one is saved
+static REVISIONS_KEPT: LazyLock<u32> = LazyLock::new(|| env_or("MEAL_REVISIONS_KEPT", 50).max(1));
+
+pub fn check_or_create_db_tables() -> ApiResult<()> {
     let conn = Connection::open(DB_FILENAME)?;
 
     // table of all canteens
@@ -25,15 +36,89 @@ pub fn check_or_create_db_tables() -> rusqlite::Result<()> {
             mensa_id integer,
             date text,
             json_text text,
+            last_changed text,
+            last_diff text,
+            foreign key (mensa_id) references mensen(mensa_id)
+        )",
+    )?
+    .execute([])?;
+
+    // every stored version of a canteen day, numbered from 1
+    conn.prepare(
+        "create table if not exists meal_revisions (
+            mensa_id integer not null,
+            date text not null,
+            revision integer not null,
+            json_text text not null,
+            created_at text not null,
+            primary key (mensa_id, date, revision),
             foreign key (mensa_id) references mensen(mensa_id)
         )",
     )?
     .execute([])?;
 
+    // other names of canteens, e.g. StuWe names of renamed canteens or of canteens merged into another
+    conn.prepare(
+        "create table if not exists canteen_aliases (
+            alias text primary key,
+            mensa_id integer not null,
+            foreign key (mensa_id) references mensen(mensa_id)
+        )",
+    )?
+    .execute([])?;
+
+    // outgoing webhook subscriptions, canteens and kinds are JSON arrays (NULL = all)
+    conn.prepare(
+        "create table if not exists webhooks (
+            id integer primary key autoincrement,
+            url text not null,
+            secret text not null,
+            canteens text,
+            kinds text,
+            scope text not null default 'today',
+            enabled integer not null default 1,
+            failure_count integer not null default 0,
+            created_at text not null,
+            last_delivery_at text,
+            last_error text
+        )",
+    )?
+    .execute([])?;
+
+    // DBs created before these columns were added
+    for (table, column, definition) in [
+        ("meals", "last_changed", "text"),
+        ("meals", "last_diff", "text"),
+        ("webhooks", "scope", "text not null default 'today'"),
+    ] {
+        let has_column = conn
+            .prepare("select 1 from pragma_table_info(?1) where name = ?2")?
+            .exists([table, column])?;
+        if !has_column {
+            conn.execute(
+                &format!("alter table {} add column {} {}", table, column, definition),
+                [],
+            )?;
+        }
+    }
+
+    // days stored before revisions were kept start with their current meals as revision 1
+    conn.execute(
+        "insert into meal_revisions (mensa_id, date, revision, json_text, created_at)
+            select mensa_id, date, 1, json_text, coalesce(last_changed, ?1) from meals m
+            where not exists (
+                select 1 from meal_revisions r where r.mensa_id = m.mensa_id and r.date = m.date
+            )",
+        params![Utc::now().to_rfc3339()],
+    )?;
+
     Ok(())
 }
 
-pub fn add_canteen_id_db(id: u32, name: &str) -> rusqlite::Result<()> {
+pub fn add_canteen_id_db(id: u32, name: &str) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["add_canteen_id_db"])
+        .start_timer();
     let conn = Connection::open(DB_FILENAME)?;
     let mut stmt = conn.prepare_cached(
         "replace into mensen (mensa_id, mensa_name)
@@ -44,24 +129,81 @@ pub fn add_canteen_id_db(id: u32, name: &str) -> rusqlite::Result<()> {
     Ok(())
 }
 
-pub async fn save_meal_to_db(date: &str, canteen_id: u32, json_text: &str) -> rusqlite::Result<()> {
-    let conn = Connection::open(DB_FILENAME)?;
-    conn.execute(
+pub async fn save_meal_to_db(
+    date: &str,
+    canteen_id: u32,
+    json_text: &str,
+    diff_json_text: Option<&str>,
+    revision: u32,
+) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["save_meal_to_db"])
+        .start_timer();
+    let mut conn = Connection::open(DB_FILENAME)?;
+    let tx = conn.transaction()?;
+    let now = Utc::now().to_rfc3339();
+    tx.execute(
         "delete from meals where mensa_id = ?1 and date = ?2",
         params![canteen_id, date],
     )?;
-
-    let mut stmt = conn.prepare_cached(
-        "insert into meals (mensa_id, date, json_text)
-            values (?1, ?2, ?3)",
+    tx.execute(
+        "insert into meals (mensa_id, date, json_text, last_changed, last_diff)
+            values (?1, ?2, ?3, ?4, ?5)",
+        params![canteen_id, date, json_text, now, diff_json_text],
     )?;
-
-    stmt.execute(params![canteen_id, date, json_text])?;
+    tx.execute(
+        "insert into meal_revisions (mensa_id, date, revision, json_text, created_at)
+            values (?1, ?2, ?3, ?4, ?5)",
+        params![canteen_id, date, revision, json_text, now],
+    )?;
+    tx.execute(
+        "delete from meal_revisions where mensa_id = ?1 and date = ?2 and revision <= ?3",
+        params![canteen_id, date, revision.saturating_sub(*REVISIONS_KEPT)],
+    )?;
+    tx.commit()?;
 
     Ok(())
 }
 
-pub async fn get_canteens_from_db() -> Result<BTreeMap<u32, String>> {
+// number of the newest stored revision of a canteen day
+pub fn get_latest_revision_db(canteen_id: u32, date: &str) -> ApiResult<Option<u32>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_latest_revision_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+
+    Ok(conn.query_row(
+        "select max(revision) from meal_revisions where mensa_id = ?1 and date = ?2",
+        params![canteen_id, date],
+        |row| row.get(0),
+    )?)
+}
+
+// JSON of the meal groups stored as the given revision of a canteen day
+pub fn get_meal_revision_db(
+    canteen_id: u32,
+    date: &str,
+    revision: u32,
+) -> ApiResult<Option<String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_meal_revision_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+
+    Ok(conn
+        .query_row(
+            "select json_text from meal_revisions
+                where mensa_id = ?1 and date = ?2 and revision = ?3",
+            params![canteen_id, date, revision],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+pub async fn get_canteens_from_db() -> ApiResult<BTreeMap<u32, String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_canteens_from_db"])
+        .start_timer();
     let conn = Connection::open(DB_FILENAME)?;
     let mut stmt = conn.prepare("select mensa_id, mensa_name from mensen")?;
 
@@ -78,7 +220,99 @@ pub async fn get_canteens_from_db() -> Result<BTreeMap<u32, String>> {
     Ok(canteens)
 }
 
-pub fn list_available_days_db(canteen_id: u32) -> rusqlite::Result<Vec<String>> {
+pub fn get_canteen_aliases_db() -> ApiResult<BTreeMap<String, u32>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_canteen_aliases_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare("select alias, mensa_id from canteen_aliases")?;
+    let mut rows = stmt.query([])?;
+
+    let mut aliases = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        aliases.insert(row.get(0)?, row.get(1)?);
+    }
+
+    Ok(aliases)
+}
+
+// the previous name stays an alias, so the scraper still recognizes the canteen
+pub fn rename_canteen_db(canteen_id: u32, name: &str) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["rename_canteen_db"])
+        .start_timer();
+    let mut conn = Connection::open(DB_FILENAME)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "insert or replace into canteen_aliases (alias, mensa_id)
+            select mensa_name, mensa_id from mensen where mensa_id = ?1",
+        params![canteen_id],
+    )?;
+    tx.execute(
+        "update mensen set mensa_name = ?2 where mensa_id = ?1",
+        params![canteen_id, name],
+    )?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// moves the source's name, aliases and meals to the target and deletes the source.
+// days stored for both canteens keep the target's meals
+pub fn merge_canteens_db(source_id: u32, target_id: u32) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["merge_canteens_db"])
+        .start_timer();
+    let mut conn = Connection::open(DB_FILENAME)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "update canteen_aliases set mensa_id = ?2 where mensa_id = ?1",
+        params![source_id, target_id],
+    )?;
+    tx.execute(
+        "insert or replace into canteen_aliases (alias, mensa_id)
+            select mensa_name, ?2 from mensen where mensa_id = ?1",
+        params![source_id, target_id],
+    )?;
+    tx.execute(
+        "update meals set mensa_id = ?2
+            where mensa_id = ?1 and date not in (select date from meals where mensa_id = ?2)",
+        params![source_id, target_id],
+    )?;
+    tx.execute(
+        "update meal_revisions set mensa_id = ?2
+            where mensa_id = ?1 and date not in (select date from meal_revisions where mensa_id = ?2)",
+        params![source_id, target_id],
+    )?;
+    tx.execute("delete from meals where mensa_id = ?1", params![source_id])?;
+    tx.execute(
+        "delete from meal_revisions where mensa_id = ?1",
+        params![source_id],
+    )?;
+    tx.execute("delete from mensen where mensa_id = ?1", params![source_id])?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// deletes the stored meals of a date for one canteen or (if None) all canteens,
+// returns the number of deleted canteen days
+pub fn delete_meals_db(canteen_id: Option<u32>, date: &str) -> ApiResult<usize> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["delete_meals_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+
+    Ok(conn.execute(
+        "delete from meals where (?1 is null or mensa_id = ?1) and date = ?2",
+        params![canteen_id, date],
+    )?)
+}
+
+pub fn list_available_days_db(canteen_id: u32) -> ApiResult<Vec<String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["list_available_days_db"])
+        .start_timer();
     let conn = Connection::open(DB_FILENAME)?;
     let mut stmt = conn.prepare_cached("select date from meals where mensa_id = ?1")?;
     let mut rows = stmt.query(params![canteen_id])?;
@@ -91,10 +325,139 @@ pub fn list_available_days_db(canteen_id: u32) -> rusqlite::Result<Vec<String>>
     Ok(dates)
 }
 
+// returns date → json_text of all stored days of a canteen within [from, to]
+pub fn get_jsonmeals_in_range_db(
+    canteen_id: u32,
+    from: &str,
+    to: &str,
+) -> ApiResult<BTreeMap<String, String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_jsonmeals_in_range_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(
+        "select date, json_text from meals where mensa_id = ?1 and date between ?2 and ?3",
+    )?;
+    let mut rows = stmt.query(params![canteen_id, from, to])?;
+
+    let mut days = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        days.insert(row.get(0)?, row.get(1)?);
+    }
+
+    Ok(days)
+}
+
+// returns canteen_id → stored day of all canteens with stored meals on the given date
+pub fn get_stored_days_of_date_db(date: &str) -> ApiResult<BTreeMap<u32, StoredDay>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_stored_days_of_date_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(
+        "select mensa_id, date, json_text, last_changed, last_diff from meals where date = ?1",
+    )?;
+    let mut rows = stmt.query(params![date])?;
+
+    let mut canteens = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        canteens.insert(row.get(0)?, stored_day_from_row(row, 1)?);
+    }
+
+    Ok(canteens)
+}
+
+// returns all stored days of a canteen within [from, to] including change metadata
+pub fn get_stored_days_db(canteen_id: u32, from: &str, to: &str) -> ApiResult<Vec<StoredDay>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_stored_days_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(
+        "select date, json_text, last_changed, last_diff from meals
+            where mensa_id = ?1 and date between ?2 and ?3 order by date",
+    )?;
+    let mut rows = stmt.query(params![canteen_id, from, to])?;
+
+    let mut days = vec![];
+    while let Some(row) = rows.next()? {
+        days.push(stored_day_from_row(row, 0)?);
+    }
+
+    Ok(days)
+}
+
+// reads date, json_text, last_changed, last_diff starting at column `offset`
+fn stored_day_from_row(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<StoredDay> {
+    let last_changed: Option<String> = row.get(offset + 2)?;
+    Ok(StoredDay {
+        date: row.get(offset)?,
+        json_text: row.get(offset + 1)?,
+        last_changed: last_changed
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.with_timezone(&Utc)),
+        last_diff: row.get(offset + 3)?,
+    })
+}
+
+// returns all dates within [from, to] for which any canteen has stored data
+pub fn list_scraped_days_in_range_db(from: &str, to: &str) -> ApiResult<BTreeSet<String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["list_scraped_days_in_range_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt =
+        conn.prepare_cached("select distinct date from meals where date between ?1 and ?2")?;
+    let mut rows = stmt.query(params![from, to])?;
+
+    let mut dates = BTreeSet::new();
+    while let Some(row) = rows.next()? {
+        dates.insert(row.get(0)?);
+    }
+
+    Ok(dates)
+}
+
+// returns the first date >= from with meals for the given canteen, or for any canteen if None
+pub fn get_next_open_day_db(canteen_id: Option<u32>, from: &str) -> ApiResult<Option<String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_next_open_day_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(
+        "select min(date) from meals
+            where (?1 is null or mensa_id = ?1) and date >= ?2 and json_text != '[]'",
+    )?;
+
+    Ok(stmt.query_row(params![canteen_id, from], |row| row.get(0))?)
+}
+
+// returns when meals within [from, to] were last changed, for one canteen or (if None) all canteens
+pub fn get_last_changed_db(
+    canteen_id: Option<u32>,
+    from: &str,
+    to: &str,
+) -> ApiResult<Option<DateTime<Utc>>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_last_changed_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(
+        "select max(last_changed) from meals
+            where (?1 is null or mensa_id = ?1) and date between ?2 and ?3",
+    )?;
+
+    let last_changed: Option<String> =
+        stmt.query_row(params![canteen_id, from, to], |row| row.get(0))?;
+    Ok(last_changed
+        .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+        .map(|date| date.with_timezone(&Utc)))
+}
+
 pub async fn get_meals_from_db(
     canteen_id: u32,
     requested_date: NaiveDate,
-) -> Result<Vec<MealGroup>> {
+) -> ApiResult<Vec<MealGroup>> {
     let date_str = build_date_string(requested_date);
     let json_text = get_jsonmeals_from_db(&date_str, canteen_id).await?;
     if let Some(json_text) = json_text {
@@ -104,18 +467,204 @@ pub async fn get_meals_from_db(
     }
 }
 
-async fn json_to_meal(json_text: &str) -> Result<Vec<MealGroup>> {
+pub async fn json_to_meal(json_text: &str) -> ApiResult<Vec<MealGroup>> {
     Ok(serde_json::from_str(json_text)?)
 }
 
-pub async fn get_jsonmeals_from_db(
-    date: &str,
-    canteen_id: u32,
-) -> rusqlite::Result<Option<String>> {
+pub async fn get_jsonmeals_from_db(date: &str, canteen_id: u32) -> ApiResult<Option<String>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_jsonmeals_from_db"])
+        .start_timer();
     let conn = Connection::open(DB_FILENAME)?;
     let mut stmt =
         conn.prepare_cached("select json_text from meals where (mensa_id, date) = (?1, ?2)")?;
     let mut rows = stmt.query(params![canteen_id, date])?;
 
-    Ok(rows.next().unwrap().map(|row| row.get(0).unwrap()))
+    Ok(match rows.next()? {
+        Some(row) => Some(row.get(0)?),
+        None => None,
+    })
+}
+
+pub fn count_webhooks_db() -> ApiResult<u32> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["count_webhooks_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+
+    Ok(conn.query_row("select count(*) from webhooks", [], |row| row.get(0))?)
+}
+
+pub fn add_webhook_db(new_webhook: &NewWebhook, secret: &str) -> ApiResult<Webhook> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["add_webhook_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let canteens = new_webhook
+        .canteens
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let kinds = new_webhook
+        .kinds
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+
+    conn.execute(
+        "insert into webhooks (url, secret, canteens, kinds, scope, created_at)
+            values (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            new_webhook.url,
+            secret,
+            canteens,
+            kinds,
+            scope_to_db(new_webhook.scope.unwrap_or_default()),
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    let id = conn.last_insert_rowid() as u32;
+
+    get_webhook_db(id)?.ok_or(ApiError::Internal("Created webhook is missing".to_string()))
+}
+
+pub fn get_webhook_db(id: u32) -> ApiResult<Option<Webhook>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["get_webhook_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(&format!("{} where id = ?1", WEBHOOK_SELECT))?;
+    let mut rows = stmt.query(params![id])?;
+
+    Ok(match rows.next()? {
+        Some(row) => Some(webhook_from_row(row)?),
+        None => None,
+    })
+}
+
+pub fn list_webhooks_db(enabled_only: bool) -> ApiResult<Vec<Webhook>> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["list_webhooks_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "{} where (?1 = 0 or enabled = 1) order by id",
+        WEBHOOK_SELECT
+    ))?;
+    let mut rows = stmt.query(params![enabled_only])?;
+
+    let mut webhooks = vec![];
+    while let Some(row) = rows.next()? {
+        webhooks.push(webhook_from_row(row)?);
+    }
+
+    Ok(webhooks)
+}
+
+pub fn delete_webhook_db(id: u32) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["delete_webhook_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    conn.execute("delete from webhooks where id = ?1", params![id])?;
+
+    Ok(())
+}
+
+// re-enabling also forgets earlier failures
+pub fn enable_webhook_db(id: u32) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["enable_webhook_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    conn.execute(
+        "update webhooks set enabled = 1, failure_count = 0 where id = ?1",
+        params![id],
+    )?;
+
+    Ok(())
+}
+
+pub fn record_webhook_success_db(id: u32) -> ApiResult<()> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["record_webhook_success_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    conn.execute(
+        "update webhooks set failure_count = 0, last_delivery_at = ?2, last_error = null
+            where id = ?1",
+        params![id, Utc::now().to_rfc3339()],
+    )?;
+
+    Ok(())
+}
+
+// returns whether the webhook is still enabled
+pub fn record_webhook_failure_db(id: u32, error: &str, max_failures: u32) -> ApiResult<bool> {
+    let _timer = DB_OPERATION_DURATION
+        .with_label_values(&["record_webhook_failure_db"])
+        .start_timer();
+    let conn = Connection::open(DB_FILENAME)?;
+    conn.execute(
+        "update webhooks set failure_count = failure_count + 1, last_error = ?2,
+                enabled = (failure_count + 1 < ?3)
+            where id = ?1",
+        params![id, error, max_failures],
+    )?;
+
+    let enabled = conn
+        .query_row(
+            "select enabled from webhooks where id = ?1",
+            params![id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(enabled.unwrap_or(false))
+}
+
+const WEBHOOK_SELECT: &str = "select id, url, secret, canteens, kinds, enabled, failure_count,
+    created_at, last_delivery_at, last_error, scope from webhooks";
+
+fn webhook_from_row(row: &rusqlite::Row) -> ApiResult<Webhook> {
+    let canteens: Option<String> = row.get(3)?;
+    let kinds: Option<String> = row.get(4)?;
+    let created_at: String = row.get(7)?;
+    let last_delivery_at: Option<String> = row.get(8)?;
+    let scope: String = row.get(10)?;
+
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        secret: row.get(2)?,
+        canteens: canteens
+            .map(|text| serde_json::from_str(&text))
+            .transpose()?,
+        kinds: kinds.map(|text| serde_json::from_str(&text)).transpose()?,
+        scope: scope_from_db(&scope),
+        enabled: row.get(5)?,
+        failure_count: row.get(6)?,
+        created_at: parse_timestamp(&created_at).unwrap_or(DateTime::UNIX_EPOCH),
+        last_delivery_at: last_delivery_at.as_deref().and_then(parse_timestamp),
+        last_error: row.get(9)?,
+    })
+}
+
+fn scope_to_db(scope: UpdateScope) -> &'static str {
+    match scope {
+        UpdateScope::Today => "today",
+        UpdateScope::Upcoming => "upcoming",
+    }
+}
+
+fn scope_from_db(scope: &str) -> UpdateScope {
+    match scope {
+        "upcoming" => UpdateScope::Upcoming,
+        _ => UpdateScope::Today,
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
 }
@@ -0,0 +1,265 @@
+use std::{any::Any, fmt};
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::header::{CONTENT_TYPE, RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::Serialize;
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+// every error a request can end in. clients should match on `code()`, messages may change
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidDate,
+    InvalidRange(String),
+    InvalidCanteenList,
+    InvalidFilter(String),
+    // path or query could not be deserialized
+    InvalidRequest(String),
+    CanteenNotFound,
+    WebhookNotFound,
+    RevisionNotFound,
+    // missing or wrong bearer token
+    Unauthorized,
+    NoOpenDay,
+    RouteNotFound,
+    // scraped data is missing or stale
+    NotReady(String),
+    // seconds until the client may retry
+    RateLimited(u64),
+    Overloaded,
+    Timeout,
+    Database(rusqlite::Error),
+    CorruptData(serde_json::Error),
+    Internal(String),
+}
+
+// RFC 7807 problem details
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidDate
+            | ApiError::InvalidRange(_)
+            | ApiError::InvalidCanteenList
+            | ApiError::InvalidFilter(_)
+            | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::CanteenNotFound
+            | ApiError::WebhookNotFound
+            | ApiError::RevisionNotFound
+            | ApiError::NoOpenDay
+            | ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotReady(_) | ApiError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Database(_) | ApiError::CorruptData(_) | ApiError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidDate => "invalid_date",
+            ApiError::InvalidRange(_) => "invalid_range",
+            ApiError::InvalidCanteenList => "invalid_canteen_list",
+            ApiError::InvalidFilter(_) => "invalid_filter",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::CanteenNotFound => "canteen_not_found",
+            ApiError::WebhookNotFound => "webhook_not_found",
+            ApiError::RevisionNotFound => "revision_not_found",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NoOpenDay => "no_open_day",
+            ApiError::RouteNotFound => "not_found",
+            ApiError::NotReady(_) => "not_ready",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Overloaded => "overloaded",
+            ApiError::Timeout => "timeout",
+            ApiError::Database(_) => "database_error",
+            ApiError::CorruptData(_) => "corrupt_data",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::InvalidDate => "Invalid date",
+            ApiError::InvalidRange(_) => "Invalid date range",
+            ApiError::InvalidCanteenList => "Invalid canteen list",
+            ApiError::InvalidFilter(_) => "Invalid filter",
+            ApiError::InvalidRequest(_) => "Invalid request",
+            ApiError::CanteenNotFound => "Canteen not found",
+            ApiError::WebhookNotFound => "Webhook not found",
+            ApiError::RevisionNotFound => "Revision not found",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::NoOpenDay => "No open day found",
+            ApiError::RouteNotFound => "Not found",
+            ApiError::NotReady(_) => "Not ready",
+            ApiError::RateLimited(_) => "Too many requests",
+            ApiError::Overloaded => "Server overloaded",
+            ApiError::Timeout => "Request timed out",
+            ApiError::Database(_) => "Database error",
+            ApiError::CorruptData(_) => "Stored meals are corrupt",
+            ApiError::Internal(_) => "Internal server error",
+        }
+    }
+
+    // for 429/503, when the client should try again
+    fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            ApiError::RateLimited(secs) => Some(*secs),
+            ApiError::Overloaded => Some(1),
+            _ => None,
+        }
+    }
+
+    // failures of this service itself, their details are not meant for clients
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Database(_) | ApiError::CorruptData(_) | ApiError::Internal(_)
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidDate => write!(
+                f,
+                "Dates must be %Y-%m-%d, 'today', 'tomorrow', 'next_open' or a weekday"
+            ),
+            ApiError::InvalidRange(message)
+            | ApiError::InvalidFilter(message)
+            | ApiError::InvalidRequest(message)
+            | ApiError::NotReady(message) => write!(f, "{}", message),
+            ApiError::InvalidCanteenList => {
+                write!(f, "'canteens' must be a comma separated list of IDs")
+            }
+            ApiError::CanteenNotFound => write!(f, "No canteen with this ID exists"),
+            ApiError::WebhookNotFound => write!(f, "No webhook with this ID exists"),
+            ApiError::RevisionNotFound => {
+                write!(f, "No revision with this number is stored for this day")
+            }
+            ApiError::Unauthorized => write!(f, "A valid bearer token is required"),
+            ApiError::NoOpenDay => write!(f, "No open day is stored yet"),
+            ApiError::RouteNotFound => write!(f, "No such endpoint"),
+            ApiError::RateLimited(secs) => write!(f, "Rate limit exceeded, retry in {}s", secs),
+            ApiError::Overloaded => write!(f, "Too many concurrent requests, retry later"),
+            ApiError::Timeout => write!(f, "The request took too long to process"),
+            ApiError::Database(e) => write!(f, "Database error: {}", e),
+            ApiError::CorruptData(e) => write!(f, "Stored meals are corrupt: {}", e),
+            ApiError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::CorruptData(e)
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        // internals are only logged, clients get a generic message
+        let detail = if self.is_internal() {
+            log::error!("Request failed: {}", self);
+            self.title().to_string()
+        } else {
+            self.to_string()
+        };
+
+        let problem = Problem {
+            problem_type: format!("urn:mensa-api:problem:{}", self.code()),
+            title: self.title(),
+            status: status.as_u16(),
+            detail,
+            code: self.code(),
+        };
+
+        let mut response = (
+            status,
+            [(CONTENT_TYPE, "application/problem+json")],
+            serde_json::to_string(&problem).unwrap(),
+        )
+            .into_response();
+        if let Some(secs) = self.retry_after_secs() {
+            response.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+
+        response
+    }
+}
+
+// Path/Query/Json whose rejections are problem responses as well
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+pub async fn route_not_found() -> ApiError {
+    ApiError::RouteNotFound
+}
+
+// used by CatchPanicLayer, so a panicking handler still answers with a problem
+pub fn panic_to_response(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown panic".to_string());
+
+    ApiError::Internal(message).into_response()
+}
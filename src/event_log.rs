@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, RwLock,
+    },
+};
+
+use chrono::Utc;
+use futures_util::{stream, Stream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{metrics::BROADCAST_LAGGED, types::CanteenMealDiff};
+
+// how many broadcast diffs are kept for clients resuming a stream
+const EVENT_LOG_CAPACITY: usize = 500;
+
+// diffs buffered for each receiver of the broadcast channel, enough for an update changing
+// every canteen on all 7 scraped days. receivers lagging further behind catch up from the log
+pub const BROADCAST_CAPACITY: usize = 256;
+
+// seeded with the startup time, so sequence numbers keep increasing across restarts
+static NEXT_SEQ: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(Utc::now().timestamp_millis() as u64));
+
+static EVENT_LOG: LazyLock<RwLock<VecDeque<CanteenMealDiff>>> =
+    LazyLock::new(|| RwLock::new(VecDeque::with_capacity(EVENT_LOG_CAPACITY)));
+
+// assigns the next sequence number and keeps the diff for replays
+pub fn record(mut diff: CanteenMealDiff) -> CanteenMealDiff {
+    let mut event_log = EVENT_LOG.write().unwrap();
+    diff.seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    if event_log.len() == EVENT_LOG_CAPACITY {
+        event_log.pop_front();
+    }
+    event_log.push_back(diff.clone());
+
+    diff
+}
+
+// sequence number of the newest broadcast diff
+pub fn latest_seq() -> u64 {
+    NEXT_SEQ.load(Ordering::Relaxed) - 1
+}
+
+// kept diffs with `after < seq <= until`, oldest first, and whether none of them
+// was already dropped from the log
+pub fn replay(after: u64, until: u64) -> (Vec<CanteenMealDiff>, bool) {
+    let event_log = EVENT_LOG.read().unwrap();
+    // sequence numbers are assigned without gaps, so everything before the oldest kept diff is lost
+    let first_kept = event_log
+        .front()
+        .map_or_else(|| NEXT_SEQ.load(Ordering::Relaxed), |diff| diff.seq);
+    let diffs = event_log
+        .iter()
+        .filter(|diff| diff.seq > after && diff.seq <= until)
+        .cloned()
+        .collect();
+
+    (diffs, after.saturating_add(1) >= first_kept)
+}
+
+// position of a stream subscriber in the log, so replays and lagging receivers
+// neither skip nor repeat diffs
+pub struct LogCursor {
+    last_seq: u64,
+}
+
+impl LogCursor {
+    // continues after `since`, or only follows new diffs without it
+    pub fn new(since: Option<u64>) -> Self {
+        LogCursor {
+            last_seq: since.unwrap_or_else(latest_seq),
+        }
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    // passes through broadcast diffs that weren't already delivered by a replay
+    pub fn advance(&mut self, diff: CanteenMealDiff) -> Option<CanteenMealDiff> {
+        if diff.seq <= self.last_seq {
+            return None;
+        }
+        self.last_seq = diff.seq;
+        Some(diff)
+    }
+
+    // the kept diffs after the cursor (e.g. after lagging behind the broadcast channel)
+    // and whether all of them could be replayed
+    pub fn catch_up(&mut self) -> (Vec<CanteenMealDiff>, bool) {
+        let (diffs, complete) = replay(self.last_seq, u64::MAX);
+        if let Some(last) = diffs.last() {
+            self.last_seq = last.seq;
+        }
+        (diffs, complete)
+    }
+}
+
+// a diff for a stream subscriber, or the gap left by diffs that were dropped from the log
+// before the subscriber could catch up
+pub enum LogEvent {
+    Diff(Box<CanteenMealDiff>),
+    Lost { after_seq: u64 },
+}
+
+// the kept diffs after the cursor followed by the broadcast ones. subscribers lagging behind
+// the channel (labelled `subscriber` in the metrics) get the missed diffs from the log
+pub fn follow(
+    rx: broadcast::Receiver<CanteenMealDiff>,
+    cursor: LogCursor,
+    subscriber: &'static str,
+) -> impl Stream<Item = LogEvent> {
+    stream::unfold(
+        (rx, cursor, VecDeque::new(), true),
+        move |(mut rx, mut cursor, mut pending, mut catch_up)| async move {
+            loop {
+                if catch_up {
+                    catch_up = false;
+                    let after_seq = cursor.last_seq();
+                    let (diffs, complete) = cursor.catch_up();
+                    if !complete {
+                        pending.push_back(LogEvent::Lost { after_seq });
+                    }
+                    pending.extend(diffs.into_iter().map(|diff| LogEvent::Diff(Box::new(diff))));
+                }
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (rx, cursor, pending, catch_up)));
+                }
+
+                match rx.recv().await {
+                    Ok(diff) => pending.extend(
+                        cursor
+                            .advance(diff)
+                            .map(|diff| LogEvent::Diff(Box::new(diff))),
+                    ),
+                    Err(RecvError::Lagged(missed)) => {
+                        BROADCAST_LAGGED
+                            .with_label_values(&[subscriber])
+                            .inc_by(missed);
+                        catch_up = true;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{stuwe_request_funcs::diff_canteen_meals, types::CanteenMealsDay};
+
+    // the log is global, tests recording into it must not interleave
+    static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+    fn diff(seq: u64) -> CanteenMealDiff {
+        let day = CanteenMealsDay {
+            canteen_id: 106,
+            meal_groups: vec![],
+        };
+        CanteenMealDiff {
+            seq,
+            ..diff_canteen_meals("2026-10-19", None, &day)
+        }
+    }
+
+    fn seqs(diffs: &[CanteenMealDiff]) -> Vec<u64> {
+        diffs.iter().map(|diff| diff.seq).collect()
+    }
+
+    #[test]
+    fn advance_skips_delivered_seqs() {
+        let mut cursor = LogCursor { last_seq: 10 };
+
+        assert!(cursor.advance(diff(10)).is_none());
+        assert_eq!(cursor.advance(diff(11)).map(|diff| diff.seq), Some(11));
+        // e.g. broadcast after it was already replayed
+        assert!(cursor.advance(diff(11)).is_none());
+        assert!(cursor.advance(diff(9)).is_none());
+        assert_eq!(cursor.advance(diff(12)).map(|diff| diff.seq), Some(12));
+        assert_eq!(cursor.last_seq(), 12);
+    }
+
+    #[test]
+    fn replay_is_bounded_and_complete() {
+        let _lock = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let first = record(diff(0)).seq;
+        let second = record(diff(0)).seq;
+        let third = record(diff(0)).seq;
+        assert_eq!((second, third), (first + 1, first + 2));
+        assert_eq!(latest_seq(), third);
+
+        let (diffs, complete) = replay(first - 1, third);
+        assert_eq!(seqs(&diffs), vec![first, second, third]);
+        assert!(complete);
+
+        let (diffs, complete) = replay(first, second);
+        assert_eq!(seqs(&diffs), vec![second]);
+        assert!(complete);
+
+        let (diffs, complete) = replay(third, u64::MAX);
+        assert!(diffs.is_empty());
+        assert!(complete);
+
+        // diffs before the startup seq were never kept
+        let (_, complete) = replay(0, third);
+        assert!(!complete);
+    }
+
+    #[test]
+    fn catch_up_after_the_log_wrapped() {
+        let _lock = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let first = record(diff(0)).seq;
+        let mut cursor = LogCursor::new(Some(first));
+        let mut last = first;
+        for _ in 0..EVENT_LOG_CAPACITY + 10 {
+            last = record(diff(0)).seq;
+        }
+
+        let (diffs, complete) = cursor.catch_up();
+        assert!(!complete);
+        assert_eq!(diffs.len(), EVENT_LOG_CAPACITY);
+        assert_eq!(
+            diffs.first().unwrap().seq,
+            last - EVENT_LOG_CAPACITY as u64 + 1
+        );
+        assert_eq!(diffs.last().unwrap().seq, last);
+        assert_eq!(cursor.last_seq(), last);
+
+        // caught up, nothing is missing anymore
+        let (diffs, complete) = cursor.catch_up();
+        assert!(diffs.is_empty());
+        assert!(complete);
+    }
+}
@@ -0,0 +1,221 @@
+use std::env;
+
+use axum::response::Response;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use http::{header::HOST, HeaderMap};
+
+use crate::{
+    constants::{CANTEEN_MAP, MAX_DATE_RANGE_DAYS},
+    date_funcs::canteen_today,
+    db_operations::{get_stored_days_db, json_to_meal},
+    error::{ApiError, ApiPath},
+    http_cache::{cached_response, private_cache},
+    render::{diff_to_html, escape_html, meal_groups_to_html},
+    stuwe_request_funcs::build_date_string,
+    types::{CanteenMealDiff, HasChanges},
+};
+
+// how many past days are kept in the feeds
+const FEED_PAST_DAYS: i64 = 14;
+
+struct FeedEntry {
+    // stable across updates of the same day
+    id: String,
+    title: String,
+    link: String,
+    updated: DateTime<Utc>,
+    content_html: String,
+}
+
+struct Feed {
+    title: String,
+    link: String,
+    updated: DateTime<Utc>,
+    entries: Vec<FeedEntry>,
+}
+
+pub async fn get_canteen_atom(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    let feed = build_feed(&headers, canteen_id).await?;
+
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<id>tag:mensa-api,2024:canteen-{}</id>
+<title>{}</title>
+<link href="{}"/>
+<updated>{}</updated>
+<author><name>mensa-api</name></author>
+"#,
+        canteen_id,
+        escape_html(&feed.title),
+        escape_html(&feed.link),
+        feed.updated.to_rfc3339()
+    );
+
+    for entry in &feed.entries {
+        xml += &format!(
+            r#"<entry>
+<id>{}</id>
+<title>{}</title>
+<link href="{}"/>
+<updated>{}</updated>
+<content type="html">{}</content>
+</entry>
+"#,
+            entry.id,
+            escape_html(&entry.title),
+            escape_html(&entry.link),
+            entry.updated.to_rfc3339(),
+            escape_html(&entry.content_html)
+        );
+    }
+    xml += "</feed>\n";
+
+    Ok(linking_response(cached_response(
+        &headers,
+        xml.into_bytes(),
+        "application/atom+xml; charset=utf-8",
+        Some(feed.updated),
+    )))
+}
+
+pub async fn get_canteen_rss(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    let feed = build_feed(&headers, canteen_id).await?;
+
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0">
+<channel>
+<title>{}</title>
+<link>{}</link>
+<description>Daily meal plans of {}</description>
+<lastBuildDate>{}</lastBuildDate>
+"#,
+        escape_html(&feed.title),
+        escape_html(&feed.link),
+        escape_html(&feed.title),
+        feed.updated.to_rfc2822()
+    );
+
+    for entry in &feed.entries {
+        // RSS readers don't track updates of an item, so every plan version gets its own guid
+        xml += &format!(
+            r#"<item>
+<guid isPermaLink="false">{}#{}</guid>
+<title>{}</title>
+<link>{}</link>
+<pubDate>{}</pubDate>
+<description>{}</description>
+</item>
+"#,
+            entry.id,
+            entry.updated.timestamp(),
+            escape_html(&entry.title),
+            escape_html(&entry.link),
+            entry.updated.to_rfc2822(),
+            escape_html(&entry.content_html)
+        );
+    }
+    xml += "</channel>\n</rss>\n";
+
+    Ok(linking_response(cached_response(
+        &headers,
+        xml.into_bytes(),
+        "application/rss+xml; charset=utf-8",
+        Some(feed.updated),
+    )))
+}
+
+async fn build_feed(headers: &HeaderMap, canteen_id: u32) -> Result<Feed, ApiError> {
+    let Some(canteen_name) = CANTEEN_MAP.read().unwrap().get(&canteen_id).cloned() else {
+        return Err(ApiError::CanteenNotFound);
+    };
+
+    let base_url = public_base_url(headers);
+    let today = canteen_today();
+    let stored_days = get_stored_days_db(
+        canteen_id,
+        &build_date_string(today - Duration::days(FEED_PAST_DAYS)),
+        &build_date_string(today + Duration::days(MAX_DATE_RANGE_DAYS - 1)),
+    )?;
+
+    let mut entries = vec![];
+    // newest first
+    for stored_day in stored_days.into_iter().rev() {
+        let Ok(date) = NaiveDate::parse_from_str(&stored_day.date, "%Y-%m-%d") else {
+            continue;
+        };
+        let meal_groups = json_to_meal(&stored_day.json_text).await?;
+        let diff = stored_day
+            .last_diff
+            .and_then(|diff| serde_json::from_str::<CanteenMealDiff>(&diff).ok())
+            .filter(|diff| diff.has_changes());
+
+        let mut title = format!("{}: {}", canteen_name, date.format("%A, %d.%m.%Y"));
+        let mut content_html = String::new();
+        if let Some(diff) = diff.as_ref() {
+            title += " (updated)";
+            content_html += &diff_to_html(diff);
+        }
+        content_html += &meal_groups_to_html(&meal_groups);
+
+        entries.push(FeedEntry {
+            id: format!(
+                "tag:mensa-api,2024:canteen-{}/{}",
+                canteen_id, stored_day.date
+            ),
+            title,
+            link: format!(
+                "{}/canteens/{}/days/{}",
+                base_url, canteen_id, stored_day.date
+            ),
+            // rows from before change tracking get the start of their day
+            updated: stored_day
+                .last_changed
+                .unwrap_or_else(|| date.and_hms_opt(0, 0, 0).unwrap().and_utc()),
+            content_html,
+        });
+    }
+
+    let updated = entries
+        .iter()
+        .map(|entry| entry.updated)
+        .max()
+        .unwrap_or(DateTime::UNIX_EPOCH);
+
+    Ok(Feed {
+        title: canteen_name,
+        link: format!("{}/canteens/{}", base_url, canteen_id),
+        updated,
+        entries,
+    })
+}
+
+// responses with links from public_base_url. without PUBLIC_URL the links come from the Host
+// header, and a forged one must not end up in shared caches
+pub fn linking_response(response: Response) -> Response {
+    if env::var("PUBLIC_URL").is_ok() {
+        response
+    } else {
+        private_cache(response)
+    }
+}
+
+// PUBLIC_URL (e.g. https://mensa.example.org) if set, otherwise derived from the Host header
+pub fn public_base_url(headers: &HeaderMap) -> String {
+    if let Ok(public_url) = env::var("PUBLIC_URL") {
+        return public_url.trim_end_matches('/').to_string();
+    }
+
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost:9090");
+    format!("http://{}", host)
+}
@@ -0,0 +1,205 @@
+use async_graphql::{
+    http::GraphiQLSource, ComplexObject, Context, EmptyMutation, ErrorExtensions, Object, Schema,
+    SimpleObject, Subscription,
+};
+use axum::response::{Html, IntoResponse};
+use chrono::NaiveDate;
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    constants::{CANTEEN_MAP, MAX_DATE_RANGE_DAYS},
+    date_funcs::resolve_date,
+    db_operations::list_available_days_db,
+    error::ApiError,
+    event_log::{self, LogCursor, LogEvent},
+    meal_filter::MealFilter,
+    metrics::WsClientGuard,
+    services::load_canteen_days,
+    types::{
+        Canteen, CanteenMealDiff, DayStatus, HasChanges, MealFilterQuery, MealGroup, UpdateScope,
+    },
+};
+
+pub type MensaSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+// a request is rate limited once, so its cost is limited as well. fields reading the DB are
+// expensive, lists of canteens and days count each of their entries (aliases count separately)
+const MAX_DEPTH: usize = 10;
+const MAX_COMPLEXITY: usize = 5000;
+const DB_FIELD_COMPLEXITY: usize = 10;
+
+pub fn build_schema(today_updated_tx: broadcast::Sender<CanteenMealDiff>) -> MensaSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(today_updated_tx)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+fn canteens_complexity(ids: &Option<Vec<u32>>, child_complexity: usize) -> usize {
+    let canteens = match ids {
+        Some(ids) => ids.len(),
+        None => CANTEEN_MAP.read().unwrap().len(),
+    };
+    canteens.max(1) * child_complexity
+}
+
+pub async fn graphiql() -> impl IntoResponse {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Day")]
+struct GqlDay {
+    date: String,
+    status: DayStatus,
+    meal_groups: Vec<MealGroup>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // all canteens, optionally restricted to the given IDs
+    #[graphql(complexity = "canteens_complexity(&ids, child_complexity)")]
+    async fn canteens(&self, ids: Option<Vec<u32>>) -> Vec<Canteen> {
+        CANTEEN_MAP
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| ids.as_ref().is_none_or(|ids| ids.contains(id)))
+            .map(|(id, name)| Canteen {
+                id: *id,
+                name: name.clone(),
+            })
+            .collect()
+    }
+
+    async fn canteen(&self, id: u32) -> Option<Canteen> {
+        CANTEEN_MAP.read().unwrap().get(&id).map(|name| Canteen {
+            id,
+            name: name.clone(),
+        })
+    }
+}
+
+#[ComplexObject]
+impl Canteen {
+    #[graphql(complexity = "DB_FIELD_COMPLEXITY")]
+    async fn available_days(&self) -> async_graphql::Result<Vec<String>> {
+        list_available_days_db(self.id).map_err(to_graphql_error)
+    }
+
+    // date is either %Y-%m-%d or an alias like 'today' or 'next_open'
+    #[graphql(complexity = "DB_FIELD_COMPLEXITY + child_complexity")]
+    async fn day(
+        &self,
+        date: String,
+        filter: Option<MealFilterQuery>,
+    ) -> async_graphql::Result<GqlDay> {
+        let date = resolve_date(&date, Some(self.id)).map_err(to_graphql_error)?;
+        let days = self.load_days(date, date, filter).await?;
+
+        days.into_iter()
+            .next()
+            .ok_or_else(|| to_graphql_error(ApiError::Internal("Requested day is missing".into())))
+    }
+
+    // at most MAX_DATE_RANGE_DAYS, like the REST range endpoint
+    #[graphql(complexity = "DB_FIELD_COMPLEXITY + MAX_DATE_RANGE_DAYS as usize * child_complexity")]
+    async fn days(
+        &self,
+        from: String,
+        to: String,
+        filter: Option<MealFilterQuery>,
+    ) -> async_graphql::Result<Vec<GqlDay>> {
+        let from = resolve_date(&from, Some(self.id)).map_err(to_graphql_error)?;
+        let to = resolve_date(&to, Some(self.id)).map_err(to_graphql_error)?;
+
+        self.load_days(from, to, filter).await
+    }
+}
+
+impl Canteen {
+    async fn load_days(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        filter: Option<MealFilterQuery>,
+    ) -> async_graphql::Result<Vec<GqlDay>> {
+        let filter = MealFilter::try_from(filter.unwrap_or_default()).map_err(to_graphql_error)?;
+        let days = load_canteen_days(self.id, from, to, &filter)
+            .await
+            .map_err(to_graphql_error)?;
+
+        Ok(days
+            .into_iter()
+            .map(|(date, day)| GqlDay {
+                date,
+                status: day.status,
+                meal_groups: day.meal_groups,
+            })
+            .collect())
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    // emits a diff whenever a canteen's plan for today (or with scope UPCOMING, any upcoming day) changes
+    async fn today_updated(
+        &self,
+        ctx: &Context<'_>,
+        canteen_ids: Option<Vec<u32>>,
+        #[graphql(default)] scope: UpdateScope,
+    ) -> impl Stream<Item = CanteenMealDiff> {
+        let rx = ctx
+            .data_unchecked::<broadcast::Sender<CanteenMealDiff>>()
+            .subscribe();
+
+        // dropped together with the stream when the client unsubscribes
+        let client_guard = WsClientGuard::new("graphql");
+
+        // lagging subscribers catch up from the event log, diffs dropped from it are skipped
+        event_log::follow(rx, LogCursor::new(None), "graphql").filter_map(move |event| {
+            let _ = &client_guard;
+            match event {
+                LogEvent::Diff(diff) => Some(*diff).filter(|diff| {
+                    diff.has_changes()
+                        && scope.includes(diff)
+                        && canteen_ids
+                            .as_ref()
+                            .is_none_or(|ids| ids.contains(&diff.canteen_id))
+                }),
+                LogEvent::Lost { after_seq } => {
+                    log::warn!("GraphQL subscriber lost the diffs after {}", after_seq);
+                    None
+                }
+            }
+        })
+    }
+}
+
+// same codes as the problem responses of the REST API
+fn to_graphql_error(err: ApiError) -> async_graphql::Error {
+    if err.is_internal() {
+        log::error!("GraphQL request failed: {}", err);
+    }
+    let message = if err.is_internal() {
+        "Internal server error".to_string()
+    } else {
+        err.to_string()
+    };
+
+    async_graphql::Error::new(message).extend_with(|_, extensions| {
+        extensions.set("status", err.status().as_u16());
+        extensions.set("code", err.code());
+    })
+}
@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, env, sync::atomic::Ordering};
+
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{
+    constants::{CANTEEN_MAP, CRON_PAUSED, DEFAULT_READY_MAX_AGE_SECS, SCRAPE_STATUS},
+    error::ApiError,
+    stuwe_request_funcs::build_date_string,
+    types::ScrapeStatus,
+};
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    last_success: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    ready: bool,
+    max_age_secs: i64,
+    last_success: Option<DateTime<Utc>>,
+    canteens: usize,
+    cron_paused: bool,
+    dates: BTreeMap<String, ScrapeStatus>,
+}
+
+// liveness: the process is up and serving requests
+pub async fn get_health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// readiness: the cached meals are fresh enough to be served
+pub async fn get_ready() -> Result<Json<ReadyResponse>, ApiError> {
+    let max_age_secs = ready_max_age_secs();
+
+    match last_success() {
+        Some(last_success) if Utc::now() - last_success <= Duration::seconds(max_age_secs) => {
+            Ok(Json(ReadyResponse { last_success }))
+        }
+        Some(last_success) => Err(ApiError::NotReady(format!(
+            "Last successful scrape was at {}, more than {}s ago",
+            last_success.to_rfc3339(),
+            max_age_secs
+        ))),
+        None => Err(ApiError::NotReady(
+            "No scrape has succeeded since startup".to_string(),
+        )),
+    }
+}
+
+pub async fn get_status() -> Json<StatusResponse> {
+    let max_age_secs = ready_max_age_secs();
+    let last_success = last_success();
+
+    Json(StatusResponse {
+        ready: last_success
+            .is_some_and(|last| Utc::now() - last <= Duration::seconds(max_age_secs)),
+        max_age_secs,
+        last_success,
+        canteens: CANTEEN_MAP.read().unwrap().len(),
+        cron_paused: CRON_PAUSED.load(Ordering::Relaxed),
+        dates: SCRAPE_STATUS
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(date, status)| (build_date_string(*date), status.clone()))
+            .collect(),
+    })
+}
+
+// most recent successful scrape of any date
+fn last_success() -> Option<DateTime<Utc>> {
+    SCRAPE_STATUS
+        .read()
+        .unwrap()
+        .values()
+        .filter_map(|status| status.last_success)
+        .max()
+}
+
+fn ready_max_age_secs() -> i64 {
+    env::var("READY_MAX_AGE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_READY_MAX_AGE_SECS)
+}
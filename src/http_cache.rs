@@ -0,0 +1,194 @@
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::{
+    header::{
+        CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY,
+    },
+    HeaderMap, HeaderValue, StatusCode,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// data is refreshed every 5 minutes, so caches may serve slightly stale data while revalidating
+const CACHE_CONTROL_VALUE: &str = "public, max-age=60, stale-while-revalidate=240";
+const PRIVATE_CACHE_CONTROL_VALUE: &str = "private, max-age=60";
+
+// serializes body as JSON and answers with 304 if the client's cached copy is still current
+pub fn cached_json<T: Serialize>(
+    req_headers: &HeaderMap,
+    body: &T,
+    last_modified: Option<DateTime<Utc>>,
+) -> Response {
+    let body = serde_json::to_vec(body).unwrap();
+    cached_response(req_headers, body, "application/json", last_modified)
+}
+
+pub fn cached_response(
+    req_headers: &HeaderMap,
+    body: Vec<u8>,
+    content_type: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> Response {
+    let etag = build_etag(&body);
+    // HTTP dates have second precision
+    let last_modified =
+        last_modified.map(|date| date.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(CACHE_CONTROL_VALUE));
+    if let Some(last_modified) = last_modified.as_ref() {
+        headers.insert(LAST_MODIFIED, HeaderValue::from_str(last_modified).unwrap());
+    }
+
+    if is_not_modified(req_headers, &etag, last_modified.as_deref()) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+    (headers, Body::from(body)).into_response()
+}
+
+// keeps a response out of shared caches, e.g. if it depends on request headers a client controls
+pub fn private_cache(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static(PRIVATE_CACHE_CONTROL_VALUE),
+    );
+    response
+}
+
+// for endpoints whose representation depends on the Accept header
+pub fn vary_accept(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+fn build_etag(body: &[u8]) -> String {
+    let hash = Sha256::digest(body);
+    let hex: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+fn is_not_modified(req_headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110 13.2.2)
+    if let Some(if_none_match) = req_headers.get(IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+
+    let (Some(if_modified_since), Some(last_modified)) =
+        (req_headers.get(IF_MODIFIED_SINCE), last_modified)
+    else {
+        return false;
+    };
+
+    match (
+        if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok()),
+        DateTime::parse_from_rfc2822(last_modified),
+    ) {
+        (Some(if_modified_since), Ok(last_modified)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG_VALUE: &str = "\"0123abcd\"";
+    const LAST_MODIFIED_VALUE: &str = "Mon, 19 Oct 2026 10:00:00 GMT";
+
+    fn headers(entries: &[(http::HeaderName, &str)]) -> HeaderMap {
+        entries
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn not_modified(entries: &[(http::HeaderName, &str)]) -> bool {
+        is_not_modified(&headers(entries), ETAG_VALUE, Some(LAST_MODIFIED_VALUE))
+    }
+
+    #[test]
+    fn etags_match_strong_weak_and_wildcard() {
+        assert!(not_modified(&[(IF_NONE_MATCH, "\"0123abcd\"")]));
+        assert!(not_modified(&[(IF_NONE_MATCH, "W/\"0123abcd\"")]));
+        assert!(not_modified(&[(
+            IF_NONE_MATCH,
+            "\"other\", W/\"0123abcd\""
+        )]));
+        assert!(not_modified(&[(IF_NONE_MATCH, "*")]));
+
+        assert!(!not_modified(&[(IF_NONE_MATCH, "\"other\"")]));
+        // unquoted tags are a different tag
+        assert!(!not_modified(&[(IF_NONE_MATCH, "0123abcd")]));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        assert!(!not_modified(&[
+            (IF_NONE_MATCH, "\"other\""),
+            (IF_MODIFIED_SINCE, LAST_MODIFIED_VALUE),
+        ]));
+        assert!(not_modified(&[
+            (IF_NONE_MATCH, "\"0123abcd\""),
+            (IF_MODIFIED_SINCE, "Mon, 19 Oct 2026 09:00:00 GMT"),
+        ]));
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        assert!(not_modified(&[(IF_MODIFIED_SINCE, LAST_MODIFIED_VALUE)]));
+        assert!(not_modified(&[(
+            IF_MODIFIED_SINCE,
+            "Tue, 20 Oct 2026 00:00:00 GMT"
+        )]));
+        assert!(!not_modified(&[(
+            IF_MODIFIED_SINCE,
+            "Mon, 19 Oct 2026 09:59:59 GMT"
+        )]));
+        assert!(!not_modified(&[(IF_MODIFIED_SINCE, "yesterday")]));
+
+        // nothing to compare with
+        let if_modified_since = headers(&[(IF_MODIFIED_SINCE, LAST_MODIFIED_VALUE)]);
+        assert!(!is_not_modified(&if_modified_since, ETAG_VALUE, None));
+        assert!(!not_modified(&[]));
+    }
+
+    #[test]
+    fn cached_response_answers_304_without_body() {
+        let body = b"[]".to_vec();
+        let etag = build_etag(&body);
+
+        let response = cached_response(
+            &headers(&[(IF_NONE_MATCH, &etag)]),
+            body.clone(),
+            "application/json",
+            None,
+        );
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+        assert!(response.headers().get(CONTENT_TYPE).is_none());
+
+        let response = cached_response(&HeaderMap::new(), body, "application/json", None);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+    }
+}
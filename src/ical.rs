@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use axum::response::Response;
+use chrono::{Duration, NaiveDate};
+use http::HeaderMap;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    constants::{CANTEEN_MAP, MAX_DATE_RANGE_DAYS},
+    date_funcs::canteen_today,
+    db_operations::{get_jsonmeals_in_range_db, get_last_changed_db, json_to_meal},
+    error::{ApiError, ApiPath, ApiQuery},
+    http_cache::cached_response,
+    meal_filter::MealFilter,
+    stuwe_request_funcs::build_date_string,
+    types::{IcalMode, IcalQuery, MealFilterQuery, MealGroup, SingleMeal},
+};
+
+pub async fn get_canteen_ical(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+    ApiQuery(ical_query): ApiQuery<IcalQuery>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+    let Some(canteen_name) = CANTEEN_MAP.read().unwrap().get(&canteen_id).cloned() else {
+        return Err(ApiError::CanteenNotFound);
+    };
+
+    let today = canteen_today();
+    let from = build_date_string(today);
+    let to = build_date_string(today + Duration::days(MAX_DATE_RANGE_DAYS - 1));
+
+    let stored_days = get_jsonmeals_in_range_db(canteen_id, &from, &to)?;
+    let last_changed = get_last_changed_db(Some(canteen_id), &from, &to)?;
+
+    // DTSTAMP must not change between requests, otherwise the ETag would be useless
+    let dtstamp = last_changed
+        .unwrap_or_else(|| today.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string();
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//mensa-api//menu//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape_text(&canteen_name)),
+    ];
+
+    for (date, json_text) in stored_days {
+        let Ok(date) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") else {
+            continue;
+        };
+        let meal_groups = filter.apply(json_to_meal(&json_text).await?);
+        if meal_groups.is_empty() {
+            continue;
+        }
+
+        match ical_query.mode.unwrap_or(IcalMode::Day) {
+            IcalMode::Day => {
+                let description = meal_groups
+                    .iter()
+                    .map(describe_meal_group)
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+
+                lines.append(&mut build_event(
+                    &format!("{}-{}@mensa-api", canteen_id, date.format("%Y%m%d")),
+                    &dtstamp,
+                    date,
+                    &canteen_name,
+                    &description,
+                ));
+            }
+            IcalMode::Meal => {
+                let mut uids = HashSet::new();
+                for meal_group in &meal_groups {
+                    for meal in &meal_group.sub_meals {
+                        let base_uid =
+                            meal_uid(canteen_id, date, &meal_group.meal_type, &meal.name);
+                        // the same meal listed twice in a category still needs distinct UIDs
+                        let mut uid = base_uid.clone();
+                        let mut n = 1;
+                        while !uids.insert(uid.clone()) {
+                            n += 1;
+                            uid = format!("{}-{}", base_uid, n);
+                        }
+                        lines.append(&mut build_event(
+                            &format!("{}@mensa-api", uid),
+                            &dtstamp,
+                            date,
+                            &format!("{} ({})", meal.name, canteen_name),
+                            &format!("{}\n{}", meal_group.meal_type, describe_meal(meal)),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    let body = lines
+        .iter()
+        .map(|line| fold_line(line))
+        .collect::<Vec<_>>()
+        .join("");
+
+    Ok(cached_response(
+        &headers,
+        body.into_bytes(),
+        "text/calendar; charset=utf-8",
+        last_changed,
+    ))
+}
+
+// all-day event
+fn build_event(
+    uid: &str,
+    dtstamp: &str,
+    date: NaiveDate,
+    summary: &str,
+    description: &str,
+) -> Vec<String> {
+    vec![
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", uid),
+        format!("DTSTAMP:{}", dtstamp),
+        format!("DTSTART;VALUE=DATE:{}", date.format("%Y%m%d")),
+        format!(
+            "DTEND;VALUE=DATE:{}",
+            (date + Duration::days(1)).format("%Y%m%d")
+        ),
+        format!("SUMMARY:{}", escape_text(summary)),
+        format!("DESCRIPTION:{}", escape_text(description)),
+        "TRANSP:TRANSPARENT".to_string(),
+        "END:VEVENT".to_string(),
+    ]
+}
+
+fn describe_meal_group(meal_group: &MealGroup) -> String {
+    let meals = meal_group
+        .sub_meals
+        .iter()
+        .map(describe_meal)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!("{}\n{}", meal_group.meal_type, meals)
+}
+
+fn describe_meal(meal: &SingleMeal) -> String {
+    let mut description = format!("- {}", meal.name);
+    if !meal.price.is_empty() {
+        description += &format!(" ({})", meal.price);
+    }
+    if !meal.additional_ingredients.is_empty() {
+        description += &format!("\n  {}", meal.additional_ingredients.join(", "));
+    }
+    if let Some(allergens) = meal.allergens.as_ref() {
+        description += &format!("\n  {}", allergens);
+    }
+    for variation in meal.variations.iter().flatten() {
+        description += &format!("\n  + {}", variation.name);
+        if let Some(allergens) = variation.allergens_and_add.as_ref() {
+            description += &format!(" ({})", allergens);
+        }
+    }
+
+    description
+}
+
+// derived from the meal instead of its position, so calendar clients keep their events
+// when other meals are added, removed or reordered
+fn meal_uid(canteen_id: u32, date: NaiveDate, meal_type: &str, meal_name: &str) -> String {
+    let category: String = meal_type
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    let hash = Sha256::digest(meal_name.as_bytes());
+    let hex: String = hash[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}",
+        canteen_id,
+        date.format("%Y%m%d"),
+        category,
+        hex
+    )
+}
+
+// TEXT escaping as per RFC 5545 3.3.11
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// content lines must not exceed 75 octets, continuation lines start with a space (RFC 5545 3.1)
+fn fold_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut line_len = 0;
+
+    for c in line.chars() {
+        if line_len + c.len_utf8() > 75 {
+            folded += "\r\n ";
+            line_len = 1;
+        }
+        folded.push(c);
+        line_len += c.len_utf8();
+    }
+
+    folded + "\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // physical lines without their CRLF
+    fn physical_lines(folded: &str) -> Vec<&str> {
+        folded.strip_suffix("\r\n").unwrap().split("\r\n").collect()
+    }
+
+    fn unfold(folded: &str) -> String {
+        folded.strip_suffix("\r\n").unwrap().replace("\r\n ", "")
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(
+            escape_text("Reis, Salat; Soße\\Dip\nvegan"),
+            r"Reis\, Salat\; Soße\\Dip\nvegan"
+        );
+        assert_eq!(escape_text("Gemüsecurry"), "Gemüsecurry");
+    }
+
+    #[test]
+    fn short_lines_are_not_folded() {
+        assert_eq!(fold_line("SUMMARY:Gemüsecurry"), "SUMMARY:Gemüsecurry\r\n");
+        let line = "X".repeat(75);
+        assert_eq!(fold_line(&line), format!("{}\r\n", line));
+    }
+
+    #[test]
+    fn long_lines_are_folded_at_75_octets() {
+        let line = format!("DESCRIPTION:{}", "x".repeat(150));
+        let folded = fold_line(&line);
+        let lines = physical_lines(&folded);
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].len(), 75);
+        assert_eq!(lines[1].len(), 75);
+        assert!(lines[1..].iter().all(|line| line.starts_with(' ')));
+        assert_eq!(unfold(&folded), line);
+    }
+
+    #[test]
+    fn multibyte_characters_are_not_split() {
+        // "ü" has 2 octets, 37 of them fill 74 of the 75 octets
+        let line = "ü".repeat(40);
+        let folded = fold_line(&line);
+        let lines = physical_lines(&folded);
+
+        assert_eq!(lines[0], "ü".repeat(37));
+        assert_eq!(lines[1], format!(" {}", "ü".repeat(3)));
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(unfold(&folded), line);
+
+        // 4 octet characters after an odd number of octets
+        let line = format!("S{}", "🍝".repeat(30));
+        let folded = fold_line(&line);
+        assert!(physical_lines(&folded).iter().all(|line| line.len() <= 75));
+        assert_eq!(physical_lines(&folded)[0].len(), 73);
+        assert_eq!(unfold(&folded), line);
+    }
+
+    #[test]
+    fn meal_uids_depend_on_the_meal_only() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let uid = meal_uid(106, date, "Vegetarisches Gericht", "Gemüsecurry");
+
+        assert!(uid.starts_with("106-20261019-vegetarisches-gericht-"));
+        assert_eq!(
+            uid,
+            meal_uid(106, date, "Vegetarisches Gericht", "Gemüsecurry")
+        );
+        assert_ne!(
+            uid,
+            meal_uid(106, date, "Vegetarisches Gericht", "Linsencurry")
+        );
+        assert_ne!(uid, meal_uid(106, date, "Vegan", "Gemüsecurry"));
+        assert_ne!(
+            uid,
+            meal_uid(111, date, "Vegetarisches Gericht", "Gemüsecurry")
+        );
+    }
+}
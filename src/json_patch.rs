@@ -0,0 +1,292 @@
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    constants::CANTEEN_MAP,
+    date_funcs::resolve_date,
+    db_operations::{get_latest_revision_db, get_meal_revision_db},
+    error::{ApiError, ApiPath, ApiQuery},
+    stuwe_request_funcs::build_date_string,
+    types::{MealPatch, PatchQuery},
+};
+
+// a single RFC 6902 operation, limited to the ones diffs are made of
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+// operations that turn `old` into `new`
+pub fn diff(old: &Value, new: &Value) -> Vec<PatchOperation> {
+    let mut patch = vec![];
+    diff_at("", old, new, &mut patch);
+    patch
+}
+
+// replaces the whole document, for days without a previous version
+pub fn replace_all(new: &Value) -> Vec<PatchOperation> {
+    vec![PatchOperation::Replace {
+        path: String::new(),
+        value: new.clone(),
+    }]
+}
+
+fn diff_at(path: &str, old: &Value, new: &Value, patch: &mut Vec<PatchOperation>) {
+    match (old, new) {
+        _ if old == new => {}
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{}/{}", path, escape(key));
+                match new.get(key) {
+                    Some(new_value) => diff_at(&path, old_value, new_value, patch),
+                    None => patch.push(PatchOperation::Remove { path }),
+                }
+            }
+            for (key, new_value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                patch.push(PatchOperation::Add {
+                    path: format!("{}/{}", path, escape(key)),
+                    value: new_value.clone(),
+                });
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            let common = old.len().min(new.len());
+            for (idx, (old_value, new_value)) in old.iter().zip(new).enumerate() {
+                diff_at(&format!("{}/{}", path, idx), old_value, new_value, patch);
+            }
+            // removed back to front, so the indices of the remaining elements stay valid
+            for idx in (common..old.len()).rev() {
+                patch.push(PatchOperation::Remove {
+                    path: format!("{}/{}", path, idx),
+                });
+            }
+            for (idx, new_value) in new.iter().enumerate().skip(common) {
+                patch.push(PatchOperation::Add {
+                    path: format!("{}/{}", path, idx),
+                    value: new_value.clone(),
+                });
+            }
+        }
+        _ => patch.push(PatchOperation::Replace {
+            path: path.to_string(),
+            value: new.clone(),
+        }),
+    }
+}
+
+// JSON pointer escaping (RFC 6901)
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+// patch between two stored revisions of a canteen day, by default from nothing (revision 0)
+// to the latest revision
+pub async fn get_day_patch(
+    ApiPath((canteen_id, date)): ApiPath<(u32, String)>,
+    ApiQuery(query): ApiQuery<PatchQuery>,
+) -> Result<Response, ApiError> {
+    if CANTEEN_MAP.read().unwrap().get(&canteen_id).is_none() {
+        return Err(ApiError::CanteenNotFound);
+    }
+    let date = build_date_string(resolve_date(&date, Some(canteen_id))?);
+
+    let to = match query.to {
+        Some(to) => to,
+        None => get_latest_revision_db(canteen_id, &date)?.ok_or(ApiError::RevisionNotFound)?,
+    };
+    let from = query.from.unwrap_or(0);
+    if from > to {
+        return Err(ApiError::InvalidRequest(
+            "from must not be after to".to_string(),
+        ));
+    }
+
+    let load = |revision: u32| -> Result<Value, ApiError> {
+        if revision == 0 {
+            return Ok(Value::Array(vec![]));
+        }
+        let json_text =
+            get_meal_revision_db(canteen_id, &date, revision)?.ok_or(ApiError::RevisionNotFound)?;
+        Ok(serde_json::from_str(&json_text)?)
+    };
+    let patch = diff(&load(from)?, &load(to)?);
+
+    Ok(Json(MealPatch {
+        seq: None,
+        canteen_id,
+        date,
+        from_revision: from,
+        revision: to,
+        patch,
+    })
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // applies a patch the way RFC 6902 clients do
+    fn apply(doc: &Value, patch: &[PatchOperation]) -> Value {
+        let mut doc = doc.clone();
+        for operation in patch {
+            let path = match operation {
+                PatchOperation::Add { path, .. }
+                | PatchOperation::Remove { path }
+                | PatchOperation::Replace { path, .. } => path,
+            };
+            if path.is_empty() {
+                match operation {
+                    PatchOperation::Replace { value, .. } => doc = value.clone(),
+                    _ => panic!("only replace may target the whole document"),
+                }
+                continue;
+            }
+
+            let (parent, key) = path.rsplit_once('/').unwrap();
+            let key = key.replace("~1", "/").replace("~0", "~");
+            match doc.pointer_mut(parent).unwrap() {
+                Value::Object(object) => match operation {
+                    PatchOperation::Add { value, .. } => {
+                        assert!(object.insert(key, value.clone()).is_none());
+                    }
+                    PatchOperation::Remove { .. } => {
+                        object.remove(&key).unwrap();
+                    }
+                    PatchOperation::Replace { value, .. } => {
+                        *object.get_mut(&key).unwrap() = value.clone();
+                    }
+                },
+                Value::Array(array) => {
+                    let idx: usize = key.parse().unwrap();
+                    match operation {
+                        PatchOperation::Add { value, .. } => array.insert(idx, value.clone()),
+                        PatchOperation::Remove { .. } => {
+                            array.remove(idx);
+                        }
+                        PatchOperation::Replace { value, .. } => array[idx] = value.clone(),
+                    }
+                }
+                _ => panic!("{} has no parent container", path),
+            }
+        }
+        doc
+    }
+
+    fn assert_roundtrip(old: Value, new: Value) -> Vec<PatchOperation> {
+        let patch = diff(&old, &new);
+        assert_eq!(apply(&old, &patch), new, "patch: {:?}", patch);
+        patch
+    }
+
+    #[test]
+    fn equal_documents_need_no_operations() {
+        let doc = json!([{ "meal_type": "Pasta", "sub_meals": [{ "name": "Lasagne" }] }]);
+        assert!(diff(&doc, &doc).is_empty());
+    }
+
+    #[test]
+    fn nested_objects_are_patched_in_place() {
+        let patch = assert_roundtrip(
+            json!({ "a": { "b": { "c": 1, "d": 2 }, "e": "x" } }),
+            json!({ "a": { "b": { "c": 3, "f": 4 }, "e": "x" } }),
+        );
+        assert_eq!(
+            patch,
+            vec![
+                PatchOperation::Replace {
+                    path: "/a/b/c".to_string(),
+                    value: json!(3),
+                },
+                PatchOperation::Remove {
+                    path: "/a/b/d".to_string(),
+                },
+                PatchOperation::Add {
+                    path: "/a/b/f".to_string(),
+                    value: json!(4),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_grow_at_the_end() {
+        let patch = assert_roundtrip(json!([1, 2]), json!([1, 5, 3, 4]));
+        assert_eq!(
+            patch,
+            vec![
+                PatchOperation::Replace {
+                    path: "/1".to_string(),
+                    value: json!(5),
+                },
+                PatchOperation::Add {
+                    path: "/2".to_string(),
+                    value: json!(3),
+                },
+                PatchOperation::Add {
+                    path: "/3".to_string(),
+                    value: json!(4),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_shrink_back_to_front() {
+        let patch = assert_roundtrip(json!({ "list": [1, 2, 3, 4] }), json!({ "list": [1] }));
+        assert_eq!(
+            patch,
+            vec![
+                PatchOperation::Remove {
+                    path: "/list/3".to_string(),
+                },
+                PatchOperation::Remove {
+                    path: "/list/2".to_string(),
+                },
+                PatchOperation::Remove {
+                    path: "/list/1".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_escaped() {
+        let patch = assert_roundtrip(
+            json!({ "a/b": 1, "m~n": { "~/": 1 } }),
+            json!({ "a/b": 2, "m~n": { "~/": 2 } }),
+        );
+        let paths: Vec<_> = patch
+            .iter()
+            .map(|operation| match operation {
+                PatchOperation::Replace { path, .. } => path.as_str(),
+                _ => panic!("unexpected operation {:?}", operation),
+            })
+            .collect();
+        assert_eq!(paths, vec!["/a~1b", "/m~0n/~0~1"]);
+    }
+
+    #[test]
+    fn mismatched_types_are_replaced() {
+        assert_roundtrip(json!({ "a": [1] }), json!({ "a": { "b": 1 } }));
+        assert_roundtrip(json!([{ "price": null }]), json!([{ "price": "2,90 €" }]));
+    }
+
+    #[test]
+    fn replace_all_builds_the_document_from_nothing() {
+        let new = json!([{ "meal_type": "Pasta", "sub_meals": [] }]);
+        assert_eq!(apply(&Value::Null, &replace_all(&new)), new);
+        // revision 0 of the patch endpoint is an empty list
+        assert_eq!(apply(&json!([]), &replace_all(&new)), new);
+        assert_roundtrip(json!([]), new);
+    }
+}
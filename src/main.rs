@@ -1,19 +1,40 @@
-use constants::{CANTEEN_MAP, CANTEEN_MAP_INV};
 use openmensa_funcs::init_openmensa_canteenlist;
-use std::env;
+use std::{env, net::SocketAddr};
 use tokio::{net::TcpListener, sync::broadcast};
 
+mod admin;
+mod auth;
 mod constants;
 mod cronjobs;
+mod date_funcs;
 mod db_operations;
+mod error;
+mod event_log;
+mod feeds;
+mod graphql;
+mod health;
+mod http_cache;
+mod ical;
+mod json_patch;
+mod meal_filter;
+mod menu;
+mod metrics;
 mod openmensa_funcs;
+mod push;
+mod rate_limit;
+mod render;
 mod routes;
 mod services;
+mod services_v2;
+mod snapshot;
+mod sse;
 mod stuwe_request_funcs;
 mod types;
+mod webhooks;
+mod ws;
 use cronjobs::{start_canteen_cache_job, update_cache};
-use db_operations::{check_or_create_db_tables, get_canteens_from_db};
-use stuwe_request_funcs::invert_map;
+use db_operations::check_or_create_db_tables;
+use stuwe_request_funcs::load_canteen_maps;
 
 #[tokio::main]
 async fn main() {
@@ -23,14 +44,12 @@ async fn main() {
     pretty_env_logger::init_timed();
     log::info!("Starting Mensa API...");
 
+    metrics::register_metrics();
+
     //// DB setup
     check_or_create_db_tables().unwrap();
 
-    {
-        let canteens = get_canteens_from_db().await.unwrap();
-        *CANTEEN_MAP_INV.write().unwrap() = invert_map(&canteens);
-        *CANTEEN_MAP.write().unwrap() = canteens;
-    }
+    load_canteen_maps().await.unwrap();
 
     // stuwe_request_funcs::_run_benchmark().await.unwrap();
     // return;
@@ -49,10 +68,13 @@ async fn main() {
         Err(e) => log::error!("Cache update failed: {}", e),
     }
 
-    // set up broadcast channel to notify WS clients whenever today's canteen plans changed
-    let (today_updated_tx, _) = broadcast::channel(20);
+    // set up broadcast channel to notify WS clients whenever canteen plans changed.
+    // the original endpoints only pass on changes of today
+    let (today_updated_tx, _) = broadcast::channel(event_log::BROADCAST_CAPACITY);
 
     start_canteen_cache_job(today_updated_tx.clone()).await;
+    webhooks::start_webhook_dispatcher(&today_updated_tx);
+    push::start_push_dispatcher(&today_updated_tx).await;
 
     let listener = TcpListener::bind("0.0.0.0:9090")
         .await
@@ -70,7 +92,11 @@ async fn main() {
     //     std::process::exit(0);
     // }
 
-    axum::serve(listener, app)
-        .await
-        .expect("Error serving application");
+    // the peer address is needed for per-IP rate limiting
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .expect("Error serving application");
 }
@@ -0,0 +1,346 @@
+use crate::{
+    error::ApiError,
+    types::{Allergen, Diet, MealFilterQuery, MealGroup, SingleMeal},
+};
+
+#[derive(Debug, Default)]
+pub struct MealFilter {
+    exclude_allergens: Vec<String>,
+    diet: Option<Diet>,
+    max_price_cents: Option<u32>,
+    categories: Vec<String>,
+}
+
+impl TryFrom<MealFilterQuery> for MealFilter {
+    type Error = ApiError;
+
+    fn try_from(query: MealFilterQuery) -> Result<Self, Self::Error> {
+        let max_price_cents = match query.max_price {
+            None => None,
+            Some(max_price) => {
+                let euros = max_price.replace(',', ".").parse::<f64>().map_err(|_| {
+                    ApiError::InvalidFilter("'max_price' must be a price in euros".to_string())
+                })?;
+                Some((euros * 100.0).round() as u32)
+            }
+        };
+
+        Ok(MealFilter {
+            exclude_allergens: split_list(query.exclude_allergens.as_deref()),
+            diet: query.diet,
+            max_price_cents,
+            categories: split_list(query.category.as_deref()),
+        })
+    }
+}
+
+impl MealFilter {
+    // removes all meals not matching the filter, then all groups that ended up empty
+    pub fn apply(&self, meal_groups: Vec<MealGroup>) -> Vec<MealGroup> {
+        meal_groups
+            .into_iter()
+            .filter(|group| {
+                self.categories.is_empty()
+                    || self.categories.contains(&group.meal_type.to_lowercase())
+            })
+            .filter_map(|mut group| {
+                let meal_type = group.meal_type.clone();
+                group.sub_meals = group
+                    .sub_meals
+                    .into_iter()
+                    .filter(|meal| self.meal_matches(&meal_type, meal))
+                    .map(|meal| self.strip_variations(meal))
+                    .collect();
+
+                (!group.sub_meals.is_empty()).then_some(group)
+            })
+            .collect()
+    }
+
+    fn meal_matches(&self, meal_type: &str, meal: &SingleMeal) -> bool {
+        if let Some(diet) = self.diet {
+            if !meal_fits_diet(meal_type, meal, diet) {
+                return false;
+            }
+        }
+
+        if let Some(max_price_cents) = self.max_price_cents {
+            // first price is the student price
+            match meal.prices_cents().first() {
+                Some(price) if *price <= max_price_cents => (),
+                _ => return false,
+            }
+        }
+
+        !self.contains_excluded_allergen(&meal.allergen_list())
+    }
+
+    // variations (e.g. side dishes) containing excluded allergens are dropped, the meal itself stays
+    fn strip_variations(&self, mut meal: SingleMeal) -> SingleMeal {
+        if self.exclude_allergens.is_empty() {
+            return meal;
+        }
+
+        if let Some(variations) = meal.variations.as_mut() {
+            variations
+                .retain(|variation| !self.contains_excluded_allergen(&variation.allergen_list()));
+        }
+
+        meal
+    }
+
+    fn contains_excluded_allergen(&self, allergens: &[Allergen]) -> bool {
+        allergens.iter().any(|allergen| {
+            let code = allergen.code.to_lowercase();
+            let name = allergen.name.as_deref().map(str::to_lowercase);
+
+            self.exclude_allergens.iter().any(|excluded| {
+                // excluding a main group (e.g. "A" gluten) also excludes its subgroups ("A1" wheat)
+                code == *excluded
+                    || (excluded.len() == 1
+                        && code.starts_with(excluded.as_str())
+                        && code[1..].chars().all(|c| c.is_ascii_digit()))
+                    || name.as_ref() == Some(excluded)
+            })
+        })
+    }
+}
+
+fn meal_fits_diet(meal_type: &str, meal: &SingleMeal, diet: Diet) -> bool {
+    let has_tag = |tag: &str| {
+        meal_type.to_lowercase().contains(tag)
+            || meal
+                .additional_ingredients
+                .iter()
+                .any(|ingredient| ingredient.to_lowercase().contains(tag))
+    };
+
+    match diet {
+        Diet::Vegan => has_tag("vegan"),
+        Diet::Vegetarian => has_tag("vegan") || has_tag("vegetari"),
+    }
+}
+
+fn split_list(list: Option<&str>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{parse_allergens, parse_prices_cents, MealVariation};
+
+    fn build_filter(query: MealFilterQuery) -> MealFilter {
+        MealFilter::try_from(query).unwrap()
+    }
+
+    fn meal(name: &str, price: &str, ingredients: &[&str], allergens: &str) -> SingleMeal {
+        SingleMeal {
+            name: name.to_string(),
+            additional_ingredients: ingredients.iter().map(|i| i.to_string()).collect(),
+            allergens: Some(allergens.to_string()),
+            variations: None,
+            price: price.to_string(),
+        }
+    }
+
+    fn meal_groups() -> Vec<MealGroup> {
+        vec![
+            MealGroup {
+                meal_type: "Vegetarisches Gericht".to_string(),
+                sub_meals: vec![meal(
+                    "Gemüsecurry",
+                    "2,90 € / 4,90 € / 6,30 €",
+                    &["Reis", "vegan"],
+                    "Allergene: Sellerie (I), Senf (J)",
+                )],
+            },
+            MealGroup {
+                meal_type: "Fleischgericht".to_string(),
+                sub_meals: vec![SingleMeal {
+                    variations: Some(vec![
+                        MealVariation {
+                            name: "Salat".to_string(),
+                            allergens_and_add: Some("A, C".to_string()),
+                        },
+                        MealVariation {
+                            name: "Pommes".to_string(),
+                            allergens_and_add: None,
+                        },
+                    ]),
+                    ..meal(
+                        "Schnitzel",
+                        "3,80 € / 5,80 € / 7,20 €",
+                        &[],
+                        "Allergene: Gluten (A), Weizen (A1), Ei (C)",
+                    )
+                }],
+            },
+        ]
+    }
+
+    fn meal_names(meal_groups: &[MealGroup]) -> Vec<&str> {
+        meal_groups
+            .iter()
+            .flat_map(|group| group.sub_meals.iter().map(|meal| meal.name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn prices_are_parsed_in_cents() {
+        assert_eq!(
+            parse_prices_cents("2,90 € / 4,90 € / 6,30 €"),
+            vec![290, 490, 630]
+        );
+        assert_eq!(parse_prices_cents("1,05€"), vec![105]);
+        assert_eq!(parse_prices_cents("3.5 €"), vec![350]);
+        assert!(parse_prices_cents("").is_empty());
+        assert!(parse_prices_cents("Preis folgt").is_empty());
+    }
+
+    #[test]
+    fn allergens_are_parsed_with_and_without_names() {
+        assert_eq!(
+            parse_allergens("Allergene: Gluten (A), Weizen (A1)"),
+            vec![
+                Allergen {
+                    code: "A".to_string(),
+                    name: Some("Gluten".to_string()),
+                },
+                Allergen {
+                    code: "A1".to_string(),
+                    name: Some("Weizen".to_string()),
+                },
+            ]
+        );
+        let codes: Vec<_> = parse_allergens("A, C, ")
+            .into_iter()
+            .map(|allergen| (allergen.code, allergen.name))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![("A".to_string(), None), ("C".to_string(), None)]
+        );
+        assert!(parse_allergens("Allergene:").is_empty());
+    }
+
+    #[test]
+    fn max_price_accepts_comma_decimals() {
+        for max_price in ["3,00", "3.00", "3"] {
+            let filter = build_filter(MealFilterQuery {
+                max_price: Some(max_price.to_string()),
+                ..Default::default()
+            });
+            assert_eq!(filter.max_price_cents, Some(300));
+            assert_eq!(
+                meal_names(&filter.apply(meal_groups())),
+                vec!["Gemüsecurry"]
+            );
+        }
+
+        // the student price counts, boundaries included
+        let filter = build_filter(MealFilterQuery {
+            max_price: Some("3,80".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            meal_names(&filter.apply(meal_groups())),
+            vec!["Gemüsecurry", "Schnitzel"]
+        );
+
+        assert!(MealFilter::try_from(MealFilterQuery {
+            max_price: Some("cheap".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn excluded_allergen_groups_include_subgroups() {
+        let filter = build_filter(MealFilterQuery {
+            exclude_allergens: Some("senf".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(meal_names(&filter.apply(meal_groups())), vec!["Schnitzel"]);
+
+        // only "A1" is listed as a number, "A" excludes both
+        let filter = build_filter(MealFilterQuery {
+            exclude_allergens: Some(" a ,".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            meal_names(&filter.apply(meal_groups())),
+            vec!["Gemüsecurry"]
+        );
+    }
+
+    #[test]
+    fn variations_with_excluded_allergens_are_stripped() {
+        let meal_groups = vec![MealGroup {
+            meal_type: "Beilage".to_string(),
+            sub_meals: vec![SingleMeal {
+                variations: Some(vec![
+                    MealVariation {
+                        name: "Salat".to_string(),
+                        allergens_and_add: Some("A, C".to_string()),
+                    },
+                    MealVariation {
+                        name: "Reis".to_string(),
+                        allergens_and_add: None,
+                    },
+                ]),
+                ..meal("Kartoffeln", "1,00 €", &[], "Allergene: Sellerie (I)")
+            }],
+        }];
+
+        let filter = build_filter(MealFilterQuery {
+            exclude_allergens: Some("c".to_string()),
+            ..Default::default()
+        });
+        let filtered = filter.apply(meal_groups.clone());
+        assert_eq!(meal_names(&filtered), vec!["Kartoffeln"]);
+        let variations = filtered[0].sub_meals[0].variations.as_ref().unwrap();
+        assert_eq!(variations.len(), 1);
+        assert_eq!(variations[0].name, "Reis");
+
+        // variations only list codes, so excluding by name doesn't strip them
+        let filter = build_filter(MealFilterQuery {
+            exclude_allergens: Some("Ei".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(filter.apply(meal_groups.clone()), meal_groups);
+    }
+
+    #[test]
+    fn diet_and_category() {
+        let filter = build_filter(MealFilterQuery {
+            diet: Some(Diet::Vegan),
+            ..Default::default()
+        });
+        assert_eq!(
+            meal_names(&filter.apply(meal_groups())),
+            vec!["Gemüsecurry"]
+        );
+
+        let filter = build_filter(MealFilterQuery {
+            diet: Some(Diet::Vegetarian),
+            ..Default::default()
+        });
+        assert_eq!(
+            meal_names(&filter.apply(meal_groups())),
+            vec!["Gemüsecurry"]
+        );
+
+        let filter = build_filter(MealFilterQuery {
+            category: Some("fleischgericht, Suppe".to_string()),
+            ..Default::default()
+        });
+        let filtered = filter.apply(meal_groups());
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].meal_type, "Fleischgericht");
+    }
+}
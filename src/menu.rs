@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use axum::response::Response;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use http::HeaderMap;
+
+use crate::{
+    constants::{CANTEEN_MAP, MAX_DATE_RANGE_DAYS},
+    date_funcs::canteen_today,
+    db_operations::list_scraped_days_in_range_db,
+    error::{ApiError, ApiQuery},
+    feeds::{linking_response, public_base_url},
+    http_cache::{cached_response, vary_accept},
+    render::{
+        content_type, negotiate_format, render_menu, Link, MenuPage, MenuSection, SectionBody,
+    },
+    stuwe_request_funcs::build_date_string,
+    types::{CanteenDay, FormatQuery, ResponseFormat},
+};
+
+// renders a page in a non-JSON format, cached like the JSON responses
+pub fn menu_response(
+    headers: &HeaderMap,
+    format: ResponseFormat,
+    page: &MenuPage,
+    last_changed: Option<DateTime<Utc>>,
+) -> Response {
+    linking_response(vary_accept(cached_response(
+        headers,
+        render_menu(page, format).into_bytes(),
+        content_type(format),
+        last_changed,
+    )))
+}
+
+// `api_prefix` is "" for v1 and "/v2" for v2, so links stay within the API version
+pub fn canteen_days_page(
+    headers: &HeaderMap,
+    api_prefix: &str,
+    canteen_id: u32,
+    mut days: BTreeMap<String, CanteenDay>,
+) -> MenuPage {
+    let base_url = format!("{}{}", public_base_url(headers), api_prefix);
+    let canteen_name = canteen_name(canteen_id);
+    let day_link = |date: NaiveDate, label: &str| Link {
+        label: label.to_string(),
+        href: format!(
+            "{}/canteens/{}/days/{}",
+            base_url,
+            canteen_id,
+            build_date_string(date)
+        ),
+    };
+
+    // a single day gets navigation to its neighbours instead of a heading
+    if days.len() == 1 {
+        if let Some((date, day)) = days.pop_first() {
+            let date = parse_date(&date);
+            return MenuPage {
+                title: format!("{} – {}", canteen_name, format_date(date)),
+                nav: vec![
+                    day_link(date - Duration::days(1), "Previous day"),
+                    day_link(date + Duration::days(1), "Next day"),
+                    Link {
+                        label: "All canteens".to_string(),
+                        href: format!("{}/days/{}", base_url, build_date_string(date)),
+                    },
+                    overview_link(headers),
+                ],
+                sections: vec![day_section(None, day)],
+            };
+        }
+    }
+
+    MenuPage {
+        title: canteen_name,
+        nav: vec![overview_link(headers)],
+        sections: days
+            .into_iter()
+            .map(|(date, day)| {
+                let date = parse_date(&date);
+                day_section(Some(day_link(date, &format_date(date))), day)
+            })
+            .collect(),
+    }
+}
+
+pub fn all_canteens_page(
+    headers: &HeaderMap,
+    api_prefix: &str,
+    date: NaiveDate,
+    days: Vec<(u32, CanteenDay)>,
+) -> MenuPage {
+    let base_url = format!("{}{}", public_base_url(headers), api_prefix);
+    let date_link = |date: NaiveDate, label: &str| Link {
+        label: label.to_string(),
+        href: format!("{}/days/{}", base_url, build_date_string(date)),
+    };
+
+    MenuPage {
+        title: format!("All canteens – {}", format_date(date)),
+        nav: vec![
+            date_link(date - Duration::days(1), "Previous day"),
+            date_link(date + Duration::days(1), "Next day"),
+            overview_link(headers),
+        ],
+        sections: days
+            .into_iter()
+            .map(|(canteen_id, day)| {
+                let heading = Link {
+                    label: canteen_name(canteen_id),
+                    href: format!(
+                        "{}/canteens/{}/days/{}",
+                        base_url,
+                        canteen_id,
+                        build_date_string(date)
+                    ),
+                };
+                day_section(Some(heading), day)
+            })
+            .collect(),
+    }
+}
+
+// navigation page linking every canteen and every upcoming day with meal plans
+pub async fn get_menu_index(
+    headers: HeaderMap,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    // there is no JSON representation of this page, so it defaults to HTML
+    let format = match negotiate_format(&headers, format_query.format) {
+        ResponseFormat::Json => ResponseFormat::Html,
+        format => format,
+    };
+    let base_url = public_base_url(&headers);
+
+    let today = canteen_today();
+    let scraped_days = list_scraped_days_in_range_db(
+        &build_date_string(today),
+        &build_date_string(today + Duration::days(MAX_DATE_RANGE_DAYS - 1)),
+    )?;
+
+    let mut sections = vec![MenuSection {
+        heading: Some(Link {
+            label: "All canteens".to_string(),
+            href: format!("{}/days/today", base_url),
+        }),
+        body: SectionBody::Links(
+            scraped_days
+                .iter()
+                .map(|date| Link {
+                    label: format_date(parse_date(date)),
+                    href: format!("{}/days/{}", base_url, date),
+                })
+                .collect(),
+        ),
+    }];
+
+    for (canteen_id, canteen_name) in CANTEEN_MAP.read().unwrap().iter() {
+        let canteen_url = format!("{}/canteens/{}", base_url, canteen_id);
+        let days_link = |label: &str, date: &str| Link {
+            label: label.to_string(),
+            href: format!("{}/days/{}", canteen_url, date),
+        };
+        sections.push(MenuSection {
+            heading: Some(days_link(canteen_name, "today")),
+            body: SectionBody::Links(vec![
+                days_link("Today", "today"),
+                days_link("Tomorrow", "tomorrow"),
+                days_link("Next open day", "next_open"),
+                Link {
+                    label: "Next 7 days".to_string(),
+                    href: format!(
+                        "{}/meals?from={}&to={}",
+                        canteen_url,
+                        build_date_string(today),
+                        build_date_string(today + Duration::days(6))
+                    ),
+                },
+            ]),
+        });
+    }
+
+    let page = MenuPage {
+        title: "Mensa API – Menus".to_string(),
+        nav: vec![],
+        sections,
+    };
+    Ok(menu_response(&headers, format, &page, None))
+}
+
+fn day_section(heading: Option<Link>, day: CanteenDay) -> MenuSection {
+    MenuSection {
+        heading,
+        body: SectionBody::Day {
+            status: day.status,
+            meal_groups: day.meal_groups,
+        },
+    }
+}
+
+fn overview_link(headers: &HeaderMap) -> Link {
+    Link {
+        label: "Overview".to_string(),
+        href: format!("{}/menu", public_base_url(headers)),
+    }
+}
+
+fn canteen_name(canteen_id: u32) -> String {
+    CANTEEN_MAP
+        .read()
+        .unwrap()
+        .get(&canteen_id)
+        .cloned()
+        .unwrap_or_else(|| format!("Canteen {}", canteen_id))
+}
+
+// dates passed in were built by build_date_string
+fn parse_date(date: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap_or_default()
+}
+
+fn format_date(date: NaiveDate) -> String {
+    date.format("%A, %d.%m.%Y").to_string()
+}
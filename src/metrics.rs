@@ -0,0 +1,195 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::header::CONTENT_TYPE;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::error::ApiError;
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mensa_http_requests_total",
+        "HTTP requests by matched route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "mensa_http_request_duration_seconds",
+        "HTTP request duration by matched route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+pub static WS_CLIENTS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "mensa_ws_clients",
+        "Currently connected WebSocket/subscription clients by channel",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+pub static BROADCAST_SENDS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "mensa_broadcast_sends_total",
+        "Canteen diffs sent to the today-updated broadcast channel"
+    )
+    .unwrap()
+});
+
+pub static BROADCAST_LAGGED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mensa_broadcast_lagged_total",
+        "Diffs missed by receivers that lagged behind, by channel",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+pub static SCRAPE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "mensa_scrape_duration_seconds",
+        "Duration of scraping a single date (download, parse)",
+        exponential_buckets(0.05, 2.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+pub static HTML_PARSE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "mensa_html_parse_duration_seconds",
+        "Duration of extracting meals from a downloaded HTML page",
+        exponential_buckets(0.001, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+pub static DB_OPERATION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "mensa_db_operation_duration_seconds",
+        "Duration of DB operations by operation",
+        &["operation"],
+        exponential_buckets(0.0001, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+pub static CHANGED_CANTEENS_PER_RUN: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "mensa_changed_canteens_per_run",
+        "Canteen days (canteen and date) whose plan changed, per cache update run",
+        vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0]
+    )
+    .unwrap()
+});
+
+pub static MEAL_UPDATES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "mensa_meal_updates_total",
+        "Stored canteen days that changed on any date"
+    )
+    .unwrap()
+});
+
+pub static CORRUPT_STORED_MEALS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "mensa_corrupt_stored_meals_total",
+        "Stored canteen days that couldn't be parsed and were replaced by the downloaded plan"
+    )
+    .unwrap()
+});
+
+pub static WEBHOOK_DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mensa_webhook_deliveries_total",
+        "Webhook deliveries by result (after retries)",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static PUSH_DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mensa_push_deliveries_total",
+        "Push notifications by service and result (after retries)",
+        &["service", "result"]
+    )
+    .unwrap()
+});
+
+// statics register lazily, this makes all metrics show up before their first use
+pub fn register_metrics() {
+    LazyLock::force(&HTTP_REQUESTS);
+    LazyLock::force(&HTTP_REQUEST_DURATION);
+    LazyLock::force(&WS_CLIENTS);
+    LazyLock::force(&BROADCAST_SENDS);
+    LazyLock::force(&BROADCAST_LAGGED);
+    LazyLock::force(&SCRAPE_DURATION);
+    LazyLock::force(&HTML_PARSE_DURATION);
+    LazyLock::force(&DB_OPERATION_DURATION);
+    LazyLock::force(&CHANGED_CANTEENS_PER_RUN);
+    LazyLock::force(&MEAL_UPDATES);
+    LazyLock::force(&CORRUPT_STORED_MEALS);
+    LazyLock::force(&WEBHOOK_DELIVERIES);
+    LazyLock::force(&PUSH_DELIVERIES);
+}
+
+// keeps a client counted in WS_CLIENTS for as long as it is alive
+pub struct WsClientGuard(&'static str);
+
+impl WsClientGuard {
+    pub fn new(channel: &'static str) -> Self {
+        WS_CLIENTS.with_label_values(&[channel]).inc();
+        WsClientGuard(channel)
+    }
+}
+
+impl Drop for WsClientGuard {
+    fn drop(&mut self) {
+        WS_CLIENTS.with_label_values(&[self.0]).dec();
+    }
+}
+
+pub async fn track_http_metrics(req: Request, next: Next) -> Response {
+    // the route pattern instead of the path, otherwise every date would be its own series
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+pub async fn get_metrics() -> Result<Response, ApiError> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response())
+}
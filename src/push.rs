@@ -0,0 +1,575 @@
+use std::{collections::BTreeMap, pin::pin, time::Duration};
+
+use chrono::NaiveDate;
+use reqwest::{Client, Url};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_cron_scheduler::{Job, JobScheduler};
+use tokio_stream::StreamExt;
+
+use crate::{
+    constants::{CANTEEN_MAP, CANTEEN_TZ},
+    date_funcs::canteen_today,
+    db_operations::{get_stored_days_of_date_db, json_to_meal},
+    event_log::{self, LogCursor, LogEvent},
+    metrics::PUSH_DELIVERIES,
+    rate_limit::env_or,
+    render::diff_to_text,
+    stuwe_request_funcs::build_date_string,
+    types::{CanteenMealDiff, ChangeKind, HasChanges, MealGroup, UpdateScope},
+};
+
+const GOTIFY_KEY_HEADER: &str = "x-gotify-key";
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// weekdays at 10:30 (canteen time)
+const DEFAULT_SUMMARY_CRON: &str = "0 30 10 * * Mon-Fri";
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PushService {
+    Ntfy,
+    Gotify,
+}
+
+impl PushService {
+    fn label(&self) -> &'static str {
+        match self {
+            PushService::Ntfy => "ntfy",
+            PushService::Gotify => "gotify",
+        }
+    }
+}
+
+// a server notifications are pushed to, configured as JSON array in PUSH_TARGETS
+#[derive(Deserialize, Debug, Clone)]
+pub struct PushTarget {
+    pub service: PushService,
+    // base URL of the ntfy or Gotify server
+    pub url: String,
+    // ntfy only
+    pub topic: Option<String>,
+    // ntfy access token (optional) or Gotify application token
+    pub token: Option<String>,
+    // filters of pushed changes (and canteens of summaries), a missing filter matches everything
+    pub canteens: Option<Vec<u32>>,
+    pub kinds: Option<Vec<ChangeKind>>,
+    #[serde(default)]
+    pub scope: UpdateScope,
+    // push meal plan changes
+    #[serde(default = "default_true")]
+    pub changes: bool,
+    // push today's menu once a day (PUSH_SUMMARY_CRON)
+    #[serde(default)]
+    pub summary: bool,
+    pub priority: Option<u8>,
+    #[serde(default)]
+    pub templates: PushTemplates,
+}
+
+// titles and messages with {placeholders}: {canteen}, {canteen_id}, {date}, {day}
+// ('today', 'tomorrow' or the weekday) and {kinds} and {changes} for changes or {menu} for summaries
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PushTemplates {
+    pub change_title: String,
+    pub change_message: String,
+    pub summary_title: String,
+    pub summary_message: String,
+}
+
+impl Default for PushTemplates {
+    fn default() -> Self {
+        PushTemplates {
+            change_title: "Mensa: {day}'s menu changed".to_string(),
+            change_message: "{canteen}\n{changes}".to_string(),
+            summary_title: "Mensa: {day}'s menu".to_string(),
+            summary_message: "{canteen}\n{menu}".to_string(),
+        }
+    }
+}
+
+// body of ntfy's JSON publishing and Gotify's message API
+#[derive(Serialize)]
+struct PushMessage<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<&'a str>,
+    title: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+}
+
+impl PushTarget {
+    fn validate(&self) -> Result<(), &'static str> {
+        match Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => return Err("url must be an absolute http(s) URL"),
+        }
+        // ntfy knows priorities 1 (min) to 5 (max), Gotify clients map 0 to 10
+        match (self.service, self.priority) {
+            (PushService::Ntfy, Some(priority)) if !(1..=5).contains(&priority) => {
+                return Err("ntfy priorities range from 1 to 5")
+            }
+            (PushService::Gotify, Some(priority)) if priority > 10 => {
+                return Err("Gotify priorities range from 0 to 10")
+            }
+            _ => {}
+        }
+        match self.service {
+            PushService::Ntfy if self.topic.as_deref().is_none_or(str::is_empty) => {
+                Err("ntfy targets need a topic")
+            }
+            PushService::Gotify if self.token.as_deref().is_none_or(str::is_empty) => {
+                Err("Gotify targets need an application token")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn wants_canteen(&self, canteen_id: u32) -> bool {
+        self.canteens
+            .as_ref()
+            .is_none_or(|canteens| canteens.contains(&canteen_id))
+    }
+
+    fn wants(&self, diff: &CanteenMealDiff) -> bool {
+        self.changes
+            && diff.has_changes()
+            && self.wants_canteen(diff.canteen_id)
+            && self.scope.includes(diff)
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| diff.change_kinds().iter().any(|kind| kinds.contains(kind)))
+    }
+}
+
+// pushes broadcast diffs and daily summaries to the targets in PUSH_TARGETS, failed deliveries
+// are retried PUSH_MAX_RETRIES times
+pub async fn start_push_dispatcher(today_updated_tx: &broadcast::Sender<CanteenMealDiff>) {
+    let targets = load_targets();
+    if targets.is_empty() {
+        return;
+    }
+    log::info!("Push notifications go to {} targets", targets.len());
+
+    let reqwest_client = Client::builder().timeout(DELIVERY_TIMEOUT).build().unwrap();
+    let retry_policy =
+        ExponentialBackoff::builder().build_with_max_retries(env_or("PUSH_MAX_RETRIES", 3));
+    let client = ClientBuilder::new(reqwest_client)
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .build();
+
+    if targets.iter().any(|target| target.changes) {
+        let rx = today_updated_tx.subscribe();
+        let client = client.clone();
+        let targets = targets.clone();
+        tokio::spawn(async move {
+            let mut events = pin!(event_log::follow(rx, LogCursor::new(None), "push"));
+            while let Some(event) = events.next().await {
+                match event {
+                    LogEvent::Diff(diff) => push_changes(&client, &targets, &diff),
+                    LogEvent::Lost { after_seq } => {
+                        log::warn!("Push dispatcher lost the diffs after {}", after_seq)
+                    }
+                }
+            }
+        });
+    }
+
+    if targets.iter().any(|target| target.summary) {
+        let schedule =
+            std::env::var("PUSH_SUMMARY_CRON").unwrap_or_else(|_| DEFAULT_SUMMARY_CRON.to_string());
+        let summary_job = Job::new_async_tz(schedule.as_str(), CANTEEN_TZ, move |_uuid, _l| {
+            let client = client.clone();
+            let targets = targets.clone();
+            Box::pin(async move { push_summaries(&client, &targets).await })
+        });
+        match summary_job {
+            Ok(summary_job) => {
+                let sched = JobScheduler::new().await.unwrap();
+                sched.add(summary_job).await.unwrap();
+                sched.start().await.unwrap();
+            }
+            Err(e) => log::error!("PUSH_SUMMARY_CRON '{}' is invalid: {}", schedule, e),
+        }
+    }
+}
+
+// invalid targets are skipped, so the others still work
+fn load_targets() -> Vec<PushTarget> {
+    let Ok(json) = std::env::var("PUSH_TARGETS") else {
+        return vec![];
+    };
+    let targets: Vec<PushTarget> = match serde_json::from_str(&json) {
+        Ok(targets) => targets,
+        Err(e) => {
+            log::error!(
+                "PUSH_TARGETS is invalid, push notifications are disabled: {}",
+                e
+            );
+            return vec![];
+        }
+    };
+
+    targets
+        .into_iter()
+        .filter(|target| match target.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Ignoring push target {}: {}", target.url, e);
+                false
+            }
+        })
+        .collect()
+}
+
+fn push_changes(client: &ClientWithMiddleware, targets: &[PushTarget], diff: &CanteenMealDiff) {
+    let mut values = placeholders(diff.canteen_id, &diff.date);
+    values.insert(
+        "kinds",
+        diff.change_kinds()
+            .iter()
+            .map(|kind| format!("{:?}", kind).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
+    values.insert("changes", diff_to_text(diff));
+
+    for target in targets.iter().filter(|target| target.wants(diff)) {
+        tokio::spawn(deliver(
+            client.clone(),
+            target.clone(),
+            render(&target.templates.change_title, &values),
+            render(&target.templates.change_message, &values),
+        ));
+    }
+}
+
+// today's stored menu of every canteen a target is interested in, closed canteens are skipped
+async fn push_summaries(client: &ClientWithMiddleware, targets: &[PushTarget]) {
+    let date = build_date_string(canteen_today());
+    let stored_days = match get_stored_days_of_date_db(&date) {
+        Ok(stored_days) => stored_days,
+        Err(e) => {
+            log::error!("Loading today's menus for push summaries failed: {}", e);
+            return;
+        }
+    };
+
+    for (canteen_id, stored_day) in stored_days {
+        let meal_groups = match json_to_meal(&stored_day.json_text).await {
+            Ok(meal_groups) if !meal_groups.is_empty() => meal_groups,
+            Ok(_) => continue,
+            Err(e) => {
+                log::error!("Stored meals of canteen {} are invalid: {}", canteen_id, e);
+                continue;
+            }
+        };
+        let mut values = placeholders(canteen_id, &date);
+        values.insert("menu", menu_to_text(&meal_groups));
+
+        for target in targets
+            .iter()
+            .filter(|target| target.summary && target.wants_canteen(canteen_id))
+        {
+            tokio::spawn(deliver(
+                client.clone(),
+                target.clone(),
+                render(&target.templates.summary_title, &values),
+                render(&target.templates.summary_message, &values),
+            ));
+        }
+    }
+}
+
+async fn deliver(client: ClientWithMiddleware, target: PushTarget, title: String, message: String) {
+    let service = target.service.label();
+    let (url, topic) = match target.service {
+        // published as JSON to the server's root, so titles aren't limited to header values
+        PushService::Ntfy => (target.url.clone(), target.topic.as_deref()),
+        PushService::Gotify => (
+            format!("{}/message", target.url.trim_end_matches('/')),
+            None,
+        ),
+    };
+    let body = serde_json::to_vec(&PushMessage {
+        topic,
+        title: &title,
+        message: &message,
+        priority: target.priority,
+    })
+    .unwrap();
+
+    let mut request = client
+        .post(url)
+        .header("content-type", "application/json")
+        .body(body);
+    if let Some(token) = target.token.as_deref() {
+        request = match target.service {
+            PushService::Ntfy => request.header("authorization", format!("Bearer {}", token)),
+            PushService::Gotify => request.header(GOTIFY_KEY_HEADER, token),
+        };
+    }
+
+    let result = request
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|response| {
+            response
+                .error_for_status()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+
+    match result {
+        Ok(()) => {
+            PUSH_DELIVERIES
+                .with_label_values(&[service, "success"])
+                .inc();
+        }
+        Err(error) => {
+            PUSH_DELIVERIES
+                .with_label_values(&[service, "failure"])
+                .inc();
+            log::warn!("Push to {} ({}) failed: {}", target.url, service, error);
+        }
+    }
+}
+
+fn placeholders(canteen_id: u32, date: &str) -> BTreeMap<&'static str, String> {
+    let canteen = CANTEEN_MAP
+        .read()
+        .unwrap()
+        .get(&canteen_id)
+        .cloned()
+        .unwrap_or_else(|| format!("Canteen {}", canteen_id));
+
+    BTreeMap::from([
+        ("canteen", canteen),
+        ("canteen_id", canteen_id.to_string()),
+        ("date", date.to_string()),
+        ("day", day_label(date)),
+    ])
+}
+
+// 'today', 'tomorrow' or the weekday, diffs only cover upcoming days
+fn day_label(date: &str) -> String {
+    let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") else {
+        return date.to_string();
+    };
+    let today = canteen_today();
+    if date == today {
+        "today".to_string()
+    } else if today.succ_opt() == Some(date) {
+        "tomorrow".to_string()
+    } else {
+        date.format("%A").to_string()
+    }
+}
+
+// short enough for a notification: one line per category
+fn menu_to_text(meal_groups: &[MealGroup]) -> String {
+    meal_groups
+        .iter()
+        .map(|meal_group| {
+            let meals = meal_group
+                .sub_meals
+                .iter()
+                .map(|meal| {
+                    if meal.price.is_empty() {
+                        meal.name.clone()
+                    } else {
+                        format!("{} ({})", meal.name, meal.price)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{}: {}", meal_group.meal_type, meals)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// replaces known {placeholders}, anything else is kept as is
+fn render(template: &str, values: &BTreeMap<&'static str, String>) -> String {
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text += &rest[..start];
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| values.get(&after[..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                text += value;
+                rest = &after[end + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = after;
+            }
+        }
+    }
+    text + rest
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn target(json: Value) -> PushTarget {
+        serde_json::from_value(json).unwrap()
+    }
+
+    // answers a single request with 200, returns its request line, lowercase headers and JSON body
+    async fn receive_one(listener: TcpListener) -> (String, BTreeMap<String, String>, Value) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let header_end = loop {
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+        };
+
+        let head = String::from_utf8(request[..header_end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap().to_string();
+        let headers: BTreeMap<String, String> = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect();
+
+        let length: usize = headers["content-length"].parse().unwrap();
+        let mut body = request[header_end + 4..].to_vec();
+        while body.len() < length {
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).await.unwrap();
+            body.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        (
+            request_line,
+            headers,
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    async fn deliver_locally(
+        target_json: Value,
+        title: &str,
+        message: &str,
+    ) -> (String, BTreeMap<String, String>, Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let mut target_json = target_json;
+        target_json["url"] = json!(url);
+        let client = ClientBuilder::new(Client::new()).build();
+
+        let received = tokio::spawn(receive_one(listener));
+        deliver(
+            client,
+            target(target_json),
+            title.to_string(),
+            message.to_string(),
+        )
+        .await;
+        received.await.unwrap()
+    }
+
+    #[test]
+    fn priorities_are_range_checked_per_service() {
+        let ntfy = |priority: u8| {
+            target(json!({
+                "service": "ntfy",
+                "url": "https://ntfy.sh",
+                "topic": "mensa",
+                "priority": priority,
+            }))
+        };
+        let gotify = |priority: u8| {
+            target(json!({
+                "service": "gotify",
+                "url": "https://gotify.example.org",
+                "token": "AppTok",
+                "priority": priority,
+            }))
+        };
+
+        assert!(ntfy(0).validate().is_err());
+        assert!(ntfy(1).validate().is_ok());
+        assert!(ntfy(5).validate().is_ok());
+        assert!(ntfy(6).validate().is_err());
+        assert!(gotify(0).validate().is_ok());
+        assert!(gotify(10).validate().is_ok());
+        assert!(gotify(11).validate().is_err());
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders() {
+        let values = BTreeMap::from([
+            ("canteen", "Mensa am Park".to_string()),
+            ("day", "today".to_string()),
+        ]);
+
+        assert_eq!(
+            render("{canteen}: {day}'s menu {unknown} {day", &values),
+            "Mensa am Park: today's menu {unknown} {day"
+        );
+        assert_eq!(render("{{day}}", &values), "{today}");
+    }
+
+    #[tokio::test]
+    async fn ntfy_is_published_as_json_to_the_root() {
+        let (request_line, headers, body) = deliver_locally(
+            json!({"service": "ntfy", "topic": "mensa", "token": "tk_abc", "priority": 4}),
+            "Title",
+            "Message",
+        )
+        .await;
+
+        assert_eq!(request_line, "POST / HTTP/1.1");
+        assert_eq!(headers["authorization"], "Bearer tk_abc");
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(
+            body,
+            json!({"topic": "mensa", "title": "Title", "message": "Message", "priority": 4})
+        );
+    }
+
+    #[tokio::test]
+    async fn gotify_messages_go_to_the_message_api() {
+        let (request_line, headers, body) = deliver_locally(
+            json!({"service": "gotify", "token": "AppTok"}),
+            "Title",
+            "Message",
+        )
+        .await;
+
+        assert_eq!(request_line, "POST /message HTTP/1.1");
+        assert_eq!(headers[GOTIFY_KEY_HEADER], "AppTok");
+        assert!(!headers.contains_key("authorization"));
+        assert_eq!(body, json!({"title": "Title", "message": "Message"}));
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use tower::{load_shed::error::Overloaded, timeout::error::Elapsed};
+
+use crate::error::ApiError;
+
+// buckets are only dropped once there are this many, refilled ones are dropped first,
+// then the least recently seen ones until half of them are left
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// probes and scrapers of the orchestrator must never be limited
+const UNLIMITED_PATHS: [&str; 4] = ["/health", "/ready", "/status", "/metrics"];
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// per-IP token bucket, configured via RATE_LIMIT_PER_SECOND, RATE_LIMIT_BURST,
+// TRUST_FORWARDED_FOR (only behind a reverse proxy) and TRUSTED_PROXY_HOPS (number of proxies
+// appending to X-Forwarded-For, the client's address is the one the outermost proxy appended)
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    trust_forwarded_for: bool,
+    trusted_proxy_hops: usize,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        RateLimiter {
+            per_second: env_or("RATE_LIMIT_PER_SECOND", 10.0),
+            burst: env_or("RATE_LIMIT_BURST", 30.0),
+            trust_forwarded_for: env_or("TRUST_FORWARDED_FOR", false),
+            trusted_proxy_hops: env_or("TRUSTED_PROXY_HOPS", 1).max(1),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // takes a token, or returns after how many seconds the next one is available
+    fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * per_second
+                    < burst
+            });
+            // with many changing addresses (e.g. IPv6) the map must not grow further, and
+            // pruning down to half keeps this from running on every new client
+            if buckets.len() > MAX_TRACKED_CLIENTS / 2 {
+                let excess = buckets.len() - MAX_TRACKED_CLIENTS / 2;
+                let mut last_refills: Vec<Instant> =
+                    buckets.values().map(|bucket| bucket.last_refill).collect();
+                let cutoff = *last_refills.select_nth_unstable(excess - 1).1;
+                buckets.retain(|_, bucket| bucket.last_refill > cutoff);
+            }
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last_refill).as_secs_f64() * self.per_second)
+            .min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.per_second).ceil() as u64)
+        }
+    }
+
+    fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        if self.trust_forwarded_for {
+            let forwarded_ip = req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                // entries left of the ones our proxies appended are sent by the client
+                .and_then(|value| value.rsplit(',').nth(self.trusted_proxy_hops - 1))
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded_ip.is_some() {
+                return forwarded_ip;
+            }
+        }
+
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+}
+
+pub async fn rate_limit(State(limiter): State<RateLimiter>, req: Request, next: Next) -> Response {
+    if !UNLIMITED_PATHS.contains(&req.uri().path()) {
+        if let Some(ip) = limiter.client_ip(&req) {
+            if let Err(retry_after_secs) = limiter.check(ip) {
+                return ApiError::RateLimited(retry_after_secs.max(1)).into_response();
+            }
+        }
+    }
+
+    next.run(req).await
+}
+
+// errors of the load shedding / timeout layers
+pub async fn handle_overload_error(err: BoxError) -> ApiError {
+    if err.is::<Overloaded>() {
+        ApiError::Overloaded
+    } else if err.is::<Elapsed>() {
+        ApiError::Timeout
+    } else {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+pub fn max_concurrent_requests() -> usize {
+    env_or("MAX_CONCURRENT_REQUESTS", 256)
+}
+
+pub fn request_timeout() -> Duration {
+    Duration::from_secs(env_or("REQUEST_TIMEOUT_SECS", 30))
+}
+
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    fn limiter(per_second: f64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            per_second,
+            burst,
+            trust_forwarded_for: false,
+            trusted_proxy_hops: 1,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    // pretends the bucket was last refilled `ago`
+    fn rewind(limiter: &RateLimiter, client: IpAddr, ago: Duration) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(&client).unwrap();
+        bucket.last_refill = bucket.last_refill.checked_sub(ago).unwrap();
+    }
+
+    #[test]
+    fn burst_is_limited_per_client() {
+        let limiter = limiter(10.0, 3.0);
+        let client = ip("192.0.2.1");
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(client), Ok(()));
+        }
+        assert_eq!(limiter.check(client), Err(1));
+        assert_eq!(limiter.check(ip("192.0.2.2")), Ok(()));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = limiter(10.0, 3.0);
+        let client = ip("2001:db8::1");
+        for _ in 0..3 {
+            limiter.check(client).unwrap();
+        }
+
+        // 2 tokens after 200ms at 10 tokens per second
+        rewind(&limiter, client, Duration::from_millis(200));
+        assert_eq!(limiter.check(client), Ok(()));
+        assert_eq!(limiter.check(client), Ok(()));
+        assert!(limiter.check(client).is_err());
+
+        // never more than the burst
+        rewind(&limiter, client, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(limiter.check(client), Ok(()));
+        }
+        assert!(limiter.check(client).is_err());
+    }
+
+    #[test]
+    fn retry_after_is_rounded_up() {
+        let limiter = limiter(0.5, 1.0);
+        let client = ip("192.0.2.1");
+
+        assert_eq!(limiter.check(client), Ok(()));
+        assert_eq!(limiter.check(client), Err(2));
+    }
+
+    fn request(forwarded_for: Option<&str>) -> Request {
+        let mut req = Request::new(Body::empty());
+        if let Some(forwarded_for) = forwarded_for {
+            req.headers_mut()
+                .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        }
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))));
+        req
+    }
+
+    #[test]
+    fn forwarded_for_is_only_used_if_trusted() {
+        let limiter = limiter(10.0, 3.0);
+        assert_eq!(
+            limiter.client_ip(&request(Some("198.51.100.7"))),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn forwarded_for_hops_are_counted_from_the_right() {
+        let forwarded_for = Some("203.0.113.9, 198.51.100.7, 192.0.2.1");
+        let hops = |trusted_proxy_hops| RateLimiter {
+            trust_forwarded_for: true,
+            trusted_proxy_hops,
+            ..limiter(10.0, 3.0)
+        };
+
+        assert_eq!(
+            hops(1).client_ip(&request(forwarded_for)),
+            Some(ip("192.0.2.1"))
+        );
+        // the client can prepend anything, only the outermost proxy's entry counts
+        assert_eq!(
+            hops(2).client_ip(&request(forwarded_for)),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(
+            hops(3).client_ip(&request(forwarded_for)),
+            Some(ip("203.0.113.9"))
+        );
+
+        // fewer entries than proxies, unparsable entries or no header: the peer address
+        assert_eq!(
+            hops(4).client_ip(&request(forwarded_for)),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            hops(1).client_ip(&request(Some("unknown"))),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(hops(1).client_ip(&request(None)), Some(ip("10.0.0.1")));
+    }
+}
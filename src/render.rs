@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+
+use http::{header::ACCEPT, HeaderMap};
+
+use crate::types::{
+    Allergen, CanteenMealDiff, ChangeKind, DayStatus, MealGroup, ResponseFormat, SingleMeal,
+};
+
+// escapes text for use in HTML and XML alike
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn meal_groups_to_html(meal_groups: &[MealGroup]) -> String {
+    if meal_groups.is_empty() {
+        return "<p>No meals available.</p>".to_string();
+    }
+
+    let mut html = String::new();
+    for meal_group in meal_groups {
+        html += &format!("<h3>{}</h3>\n<ul>\n", escape_html(&meal_group.meal_type));
+        for meal in &meal_group.sub_meals {
+            html += &format!(
+                "<li>{}</li>\n",
+                meal_to_html(meal, AllergenDisplay::Scraped)
+            );
+        }
+        html += "</ul>\n";
+    }
+
+    html
+}
+
+// how meal_to_html shows allergens: as scraped, or as codes explained by the page's legend
+#[derive(Clone, Copy)]
+enum AllergenDisplay {
+    Scraped,
+    Codes,
+}
+
+fn meal_to_html(meal: &SingleMeal, allergen_display: AllergenDisplay) -> String {
+    let mut html = format!("<strong>{}</strong>", escape_html(&meal.name));
+    if !meal.price.is_empty() {
+        html += &format!(" – {}", escape_html(&meal.price));
+    }
+    if !meal.additional_ingredients.is_empty() {
+        html += &format!(
+            "<br>{}",
+            escape_html(&meal.additional_ingredients.join(", "))
+        );
+    }
+    let allergens = match allergen_display {
+        AllergenDisplay::Scraped => meal.allergens.clone(),
+        AllergenDisplay::Codes => non_empty(allergen_codes(&meal.allergen_list()))
+            .map(|codes| format!("Allergens: {}", codes)),
+    };
+    if let Some(allergens) = allergens {
+        html += &format!("<br><small>{}</small>", escape_html(&allergens));
+    }
+    for variation in meal.variations.iter().flatten() {
+        html += &format!("<br>+ {}", escape_html(&variation.name));
+        let allergens = match allergen_display {
+            AllergenDisplay::Scraped => variation.allergens_and_add.clone(),
+            AllergenDisplay::Codes => non_empty(allergen_codes(&variation.allergen_list())),
+        };
+        if let Some(allergens) = allergens {
+            html += &format!(" <small>({})</small>", escape_html(&allergens));
+        }
+    }
+
+    html
+}
+
+// summarizes which meals were added, modified, renamed, moved or removed
+pub fn diff_to_html(diff: &CanteenMealDiff) -> String {
+    let mut html = String::new();
+    for (label, names) in diff_lines(diff) {
+        html += &format!("<li>{}: {}</li>\n", label, escape_html(&names));
+    }
+
+    if html.is_empty() {
+        html
+    } else {
+        format!("<h3>Changes</h3>\n<ul>\n{}</ul>\n", html)
+    }
+}
+
+// one "Label: meals" line per kind of change, e.g. for push notifications
+pub fn diff_to_text(diff: &CanteenMealDiff) -> String {
+    diff_lines(diff)
+        .into_iter()
+        .map(|(label, names)| format!("{}: {}", label, names))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn diff_lines(diff: &CanteenMealDiff) -> Vec<(&'static str, String)> {
+    let mut lines = vec![];
+    for (label, kind, meal_groups) in [
+        ("New", ChangeKind::New, &diff.new_meals),
+        ("Modified", ChangeKind::Modified, &diff.modified_meals),
+        ("Renamed", ChangeKind::Renamed, &diff.renamed_meals),
+        ("Moved", ChangeKind::Moved, &diff.moved_meals),
+        ("Removed", ChangeKind::Removed, &diff.removed_meals),
+    ] {
+        let Some(meal_groups) = meal_groups else {
+            continue;
+        };
+        let names = match &diff.meal_changes {
+            // field-level changes tell what exactly was changed
+            Some(meal_changes) if meal_changes.iter().any(|change| change.kind() == kind) => {
+                meal_changes
+                    .iter()
+                    .filter(|change| change.kind() == kind)
+                    .map(|change| change.summary())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            }
+            _ => meal_groups
+                .iter()
+                .flat_map(|group| group.sub_meals.iter())
+                .map(|meal| meal.name.clone())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        lines.push((label, names));
+    }
+    lines
+}
+
+pub struct Link {
+    pub label: String,
+    pub href: String,
+}
+
+// a rendered menu (or navigation) page, independent of the output format
+pub struct MenuPage {
+    pub title: String,
+    pub nav: Vec<Link>,
+    pub sections: Vec<MenuSection>,
+}
+
+pub struct MenuSection {
+    // only needed if a page has multiple sections
+    pub heading: Option<Link>,
+    pub body: SectionBody,
+}
+
+pub enum SectionBody {
+    Day {
+        status: DayStatus,
+        meal_groups: Vec<MealGroup>,
+    },
+    Links(Vec<Link>),
+}
+
+// picks the representation by ?format=, otherwise by the client's Accept header (JSON by default)
+pub fn negotiate_format(headers: &HeaderMap, format: Option<ResponseFormat>) -> ResponseFormat {
+    if let Some(format) = format {
+        return format;
+    }
+    let Some(accept) = headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()) else {
+        return ResponseFormat::Json;
+    };
+
+    let mut best: Option<(ResponseFormat, f32)> = None;
+    for media_range in accept.split(',') {
+        let mut params = media_range.split(';').map(str::trim);
+        let format = match params.next().unwrap_or_default().to_lowercase().as_str() {
+            "text/html" | "application/xhtml+xml" => ResponseFormat::Html,
+            "text/markdown" | "text/x-markdown" => ResponseFormat::Markdown,
+            "text/plain" => ResponseFormat::Text,
+            "application/json" | "application/*" | "*/*" => ResponseFormat::Json,
+            _ => continue,
+        };
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|quality| quality.parse().ok())
+            .unwrap_or(1.0);
+
+        // on equal quality the first listed type wins
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((format, quality));
+        }
+    }
+
+    best.map(|(format, _)| format)
+        .unwrap_or(ResponseFormat::Json)
+}
+
+pub fn content_type(format: ResponseFormat) -> &'static str {
+    match format {
+        ResponseFormat::Json => "application/json",
+        ResponseFormat::Html => "text/html; charset=utf-8",
+        ResponseFormat::Markdown => "text/markdown; charset=utf-8",
+        ResponseFormat::Text => "text/plain; charset=utf-8",
+    }
+}
+
+pub fn render_menu(page: &MenuPage, format: ResponseFormat) -> String {
+    match format {
+        ResponseFormat::Html => menu_to_html(page),
+        ResponseFormat::Markdown => menu_to_markdown(page),
+        // JSON never gets here, the handlers serialize their data directly
+        ResponseFormat::Text | ResponseFormat::Json => menu_to_text(page),
+    }
+}
+
+fn menu_to_html(page: &MenuPage) -> String {
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html lang="de">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{}</title>
+<style>body {{ font-family: sans-serif; max-width: 50em; margin: auto; padding: 1em; }} small {{ color: #666; }}</style>
+</head>
+<body>
+<h1>{}</h1>
+"#,
+        escape_html(&page.title),
+        escape_html(&page.title)
+    );
+    html += &nav_to_html(&page.nav);
+
+    for section in &page.sections {
+        if let Some(heading) = section.heading.as_ref() {
+            html += &format!(
+                "<h2><a href=\"{}\">{}</a></h2>\n",
+                escape_html(&heading.href),
+                escape_html(&heading.label)
+            );
+        }
+        match &section.body {
+            SectionBody::Day {
+                status,
+                meal_groups,
+            } => match status_note(*status, meal_groups) {
+                Some(note) => html += &format!("<p>{}</p>\n", note),
+                None => {
+                    for meal_group in meal_groups {
+                        html += &format!("<h3>{}</h3>\n<ul>\n", escape_html(&meal_group.meal_type));
+                        for meal in &meal_group.sub_meals {
+                            html += &format!(
+                                "<li>{}</li>\n",
+                                meal_to_html(meal, AllergenDisplay::Codes)
+                            );
+                        }
+                        html += "</ul>\n";
+                    }
+                }
+            },
+            SectionBody::Links(links) => {
+                html += "<ul>\n";
+                for link in links {
+                    html += &format!(
+                        "<li><a href=\"{}\">{}</a></li>\n",
+                        escape_html(&link.href),
+                        escape_html(&link.label)
+                    );
+                }
+                html += "</ul>\n";
+            }
+        }
+    }
+
+    let legend = allergen_legend(page);
+    if !legend.is_empty() {
+        html += "<h2>Allergens and additives</h2>\n<dl>\n";
+        for (code, name) in &legend {
+            html += &format!(
+                "<dt>{}</dt><dd>{}</dd>\n",
+                escape_html(code),
+                escape_html(name.as_deref().unwrap_or("–"))
+            );
+        }
+        html += "</dl>\n";
+    }
+
+    html + "</body>\n</html>\n"
+}
+
+fn nav_to_html(nav: &[Link]) -> String {
+    if nav.is_empty() {
+        return String::new();
+    }
+    let links = nav
+        .iter()
+        .map(|link| {
+            format!(
+                "<a href=\"{}\">{}</a>",
+                escape_html(&link.href),
+                escape_html(&link.label)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" · ");
+    format!("<nav>{}</nav>\n", links)
+}
+
+fn menu_to_markdown(page: &MenuPage) -> String {
+    let mut md = format!("# {}\n\n", page.title);
+    if !page.nav.is_empty() {
+        md += &page
+            .nav
+            .iter()
+            .map(|link| format!("[{}]({})", link.label, link.href))
+            .collect::<Vec<_>>()
+            .join(" · ");
+        md += "\n\n";
+    }
+
+    for section in &page.sections {
+        if let Some(heading) = section.heading.as_ref() {
+            md += &format!("## [{}]({})\n\n", heading.label, heading.href);
+        }
+        match &section.body {
+            SectionBody::Day {
+                status,
+                meal_groups,
+            } => match status_note(*status, meal_groups) {
+                Some(note) => md += &format!("_{}_\n\n", note),
+                None => {
+                    for meal_group in meal_groups {
+                        md += &format!("### {}\n\n", meal_group.meal_type);
+                        for meal in &meal_group.sub_meals {
+                            md += &meal_to_markdown(meal);
+                        }
+                        md += "\n";
+                    }
+                }
+            },
+            SectionBody::Links(links) => {
+                for link in links {
+                    md += &format!("- [{}]({})\n", link.label, link.href);
+                }
+                md += "\n";
+            }
+        }
+    }
+
+    let legend = allergen_legend(page);
+    if !legend.is_empty() {
+        md += "## Allergens and additives\n\n";
+        for (code, name) in &legend {
+            md += &format!("- **{}**: {}\n", code, name.as_deref().unwrap_or("–"));
+        }
+    }
+
+    md
+}
+
+fn meal_to_markdown(meal: &SingleMeal) -> String {
+    let mut md = format!("- **{}**", meal.name);
+    if !meal.price.is_empty() {
+        md += &format!(" – {}", meal.price);
+    }
+    if !meal.additional_ingredients.is_empty() {
+        md += &format!("  \n  {}", meal.additional_ingredients.join(", "));
+    }
+    let codes = allergen_codes(&meal.allergen_list());
+    if !codes.is_empty() {
+        md += &format!("  \n  _Allergens: {}_", codes);
+    }
+    md += "\n";
+    for variation in meal.variations.iter().flatten() {
+        md += &format!("  - + {}", variation.name);
+        let codes = allergen_codes(&variation.allergen_list());
+        if !codes.is_empty() {
+            md += &format!(" _({})_", codes);
+        }
+        md += "\n";
+    }
+
+    md
+}
+
+fn menu_to_text(page: &MenuPage) -> String {
+    let mut text = format!(
+        "{}\n{}\n\n",
+        page.title,
+        "=".repeat(page.title.chars().count())
+    );
+
+    for section in &page.sections {
+        if let Some(heading) = section.heading.as_ref() {
+            text += &format!(
+                "{}\n{}\n\n",
+                heading.label,
+                "-".repeat(heading.label.chars().count())
+            );
+        }
+        match &section.body {
+            SectionBody::Day {
+                status,
+                meal_groups,
+            } => match status_note(*status, meal_groups) {
+                Some(note) => text += &format!("{}\n\n", note),
+                None => {
+                    for meal_group in meal_groups {
+                        text += &format!("{}\n", meal_group.meal_type);
+                        for meal in &meal_group.sub_meals {
+                            text += &meal_to_text(meal);
+                        }
+                        text += "\n";
+                    }
+                }
+            },
+            SectionBody::Links(links) => {
+                for link in links {
+                    text += &format!("  {}: {}\n", link.label, link.href);
+                }
+                text += "\n";
+            }
+        }
+    }
+
+    let legend = allergen_legend(page);
+    if !legend.is_empty() {
+        text += "Allergens and additives\n";
+        for (code, name) in &legend {
+            text += &format!("  {:<4} {}\n", code, name.as_deref().unwrap_or("–"));
+        }
+        text += "\n";
+    }
+
+    for link in &page.nav {
+        text += &format!("{}: {}\n", link.label, link.href);
+    }
+
+    text
+}
+
+fn meal_to_text(meal: &SingleMeal) -> String {
+    let mut text = format!("  * {}", meal.name);
+    if !meal.price.is_empty() {
+        text += &format!(" – {}", meal.price);
+    }
+    text += "\n";
+    if !meal.additional_ingredients.is_empty() {
+        text += &format!("    {}\n", meal.additional_ingredients.join(", "));
+    }
+    let codes = allergen_codes(&meal.allergen_list());
+    if !codes.is_empty() {
+        text += &format!("    Allergens: {}\n", codes);
+    }
+    for variation in meal.variations.iter().flatten() {
+        text += &format!("    + {}", variation.name);
+        let codes = allergen_codes(&variation.allergen_list());
+        if !codes.is_empty() {
+            text += &format!(" ({})", codes);
+        }
+        text += "\n";
+    }
+
+    text
+}
+
+// explains why a day has no meals, None if there are meals to show
+fn status_note(status: DayStatus, meal_groups: &[MealGroup]) -> Option<&'static str> {
+    match status {
+        DayStatus::Closed => Some("Closed."),
+        DayStatus::Unknown => Some("No meal plan has been published yet."),
+        DayStatus::Open if meal_groups.is_empty() => Some("No meals match the filter."),
+        DayStatus::Open => None,
+    }
+}
+
+fn allergen_codes(allergens: &[Allergen]) -> String {
+    allergens
+        .iter()
+        .map(|allergen| allergen.code.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn non_empty(text: String) -> Option<String> {
+    (!text.is_empty()).then_some(text)
+}
+
+// all allergen codes on the page. variations only list codes, their names are taken from other meals
+fn allergen_legend(page: &MenuPage) -> BTreeMap<String, Option<String>> {
+    let mut legend: BTreeMap<String, Option<String>> = BTreeMap::new();
+    let meals = page
+        .sections
+        .iter()
+        .filter_map(|section| match &section.body {
+            SectionBody::Day { meal_groups, .. } => Some(meal_groups),
+            SectionBody::Links(_) => None,
+        })
+        .flatten()
+        .flat_map(|meal_group| meal_group.sub_meals.iter());
+
+    for meal in meals {
+        let variation_allergens = meal
+            .variations
+            .iter()
+            .flatten()
+            .flat_map(|variation| variation.allergen_list());
+        for allergen in meal.allergen_list().into_iter().chain(variation_allergens) {
+            let name = legend.entry(allergen.code).or_default();
+            if name.is_none() {
+                *name = allergen.name;
+            }
+        }
+    }
+
+    legend
+}
@@ -1,43 +1,249 @@
-use axum::{response::IntoResponse, routing::get, Router};
-use http::{header::CONTENT_TYPE, Method};
+use std::sync::LazyLock;
+
+use async_graphql_axum::{GraphQL, GraphQLSubscription};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::Request,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use chrono::{NaiveDate, NaiveTime};
+use http::{
+    header::{
+        AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LINK, RETRY_AFTER,
+    },
+    HeaderName, HeaderValue, Method,
+};
 use tokio::sync::broadcast;
-use tower_http::cors::{Any, CorsLayer};
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    cors::{Any, CorsLayer},
+};
+
+use crate::{
+    admin,
+    date_funcs::RESOLVED_DATE_HEADER,
+    error::{panic_to_response, route_not_found},
+    feeds, graphql, health, ical, json_patch, menu, metrics, openmensa_funcs, rate_limit, services,
+    services_v2, sse,
+    types::CanteenMealDiff,
+    webhooks, ws,
+};
 
-use crate::{openmensa_funcs, services, types::CanteenMealDiff};
+const DEPRECATION_HEADER: &str = "deprecation";
+const SUNSET_HEADER: &str = "sunset";
+// v1 is deprecated since v2 was released (2026-10-19), as RFC 9745 structured date
+const V1_DEPRECATED_AT: &str = "@1792368000";
+
+// planned removal of v1, configured as V1_SUNSET (%Y-%m-%d, UTC) and sent as HTTP date (RFC 8594)
+static V1_SUNSET: LazyLock<Option<HeaderValue>> = LazyLock::new(|| {
+    let sunset = std::env::var("V1_SUNSET").ok()?;
+    match NaiveDate::parse_from_str(&sunset, "%Y-%m-%d") {
+        Ok(date) => HeaderValue::from_str(
+            &date
+                .and_time(NaiveTime::MIN)
+                .and_utc()
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )
+        .ok(),
+        Err(_) => {
+            log::error!("V1_SUNSET '{}' is not a %Y-%m-%d date", sunset);
+            None
+        }
+    }
+});
 
 pub async fn app(today_updated_tx: broadcast::Sender<CanteenMealDiff>) -> Router {
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         // allow requests from any origin
         .allow_origin(Any)
-        .allow_headers([CONTENT_TYPE]);
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            IF_NONE_MATCH,
+            IF_MODIFIED_SINCE,
+            HeaderName::from_static(sse::LAST_EVENT_ID_HEADER),
+        ])
+        .expose_headers([
+            ETAG,
+            LINK,
+            RETRY_AFTER,
+            HeaderName::from_static(RESOLVED_DATE_HEADER),
+            HeaderName::from_static(DEPRECATION_HEADER),
+            HeaderName::from_static(SUNSET_HEADER),
+        ]);
 
     let today_updated_id_tx = today_updated_tx.clone();
     let today_updated_diff_tx = today_updated_tx.clone();
+    let today_updated_sse_tx = today_updated_tx.clone();
+    let cache_update_tx = today_updated_tx.clone();
+    let ws_tx = today_updated_tx.clone();
+    let admin_tokens = admin::AdminTokens::from_env();
+    let schema = graphql::build_schema(today_updated_tx.clone());
+
+    // the semaphore is shared by all routes, so the limit is global.
+    // excess requests are rejected right away instead of queueing up
+    let overload_protection = ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(rate_limit::handle_overload_error))
+        .load_shed()
+        .layer(GlobalConcurrencyLimitLayer::new(
+            rate_limit::max_concurrent_requests(),
+        ))
+        .timeout(rate_limit::request_timeout());
+
+    // unversioned JSON endpoints are v1, they stay stable but point to their v2 successor
+    let v1 = Router::new()
+        .route("/canteens", get(services::get_canteens))
+        .route("/canteens/:canteen_id", get(services::get_canteen_meta))
+        .route(
+            "/canteens/:canteen_id/days",
+            get(services::get_canteen_available_days),
+        )
+        .route(
+            "/canteens/:canteen_id/days/:date",
+            get(services::get_meals_of_day),
+        )
+        .route(
+            "/canteens/:canteen_id/meals",
+            get(services::get_meals_of_range),
+        )
+        .route("/days/:date", get(services::get_all_meals_of_day))
+        .layer(middleware::from_fn(add_deprecation_headers));
+
+    let v2 = Router::new()
+        .route("/canteens", get(services_v2::get_canteens))
+        .route("/canteens/:canteen_id", get(services_v2::get_canteen))
+        .route(
+            "/canteens/:canteen_id/days",
+            get(services_v2::get_canteen_days),
+        )
+        .route(
+            "/canteens/:canteen_id/days/:date",
+            get(services_v2::get_meals_of_day),
+        )
+        .route(
+            "/canteens/:canteen_id/days/:date/patch",
+            get(json_patch::get_day_patch),
+        )
+        .route(
+            "/canteens/:canteen_id/meals",
+            get(services_v2::get_meals_of_range),
+        )
+        .route("/days/:date", get(services_v2::get_all_meals_of_day));
+
+    // operational endpoints, only usable with one of the configured admin tokens
+    let admin = Router::new()
+        .route(
+            "/cache/update",
+            post(move |query| admin::trigger_cache_update(query, cache_update_tx)),
+        )
+        .route("/cron", get(admin::get_cron))
+        .route("/cron/pause", post(admin::pause_cron))
+        .route("/cron/resume", post(admin::resume_cron))
+        .route("/days/:date", delete(admin::clear_day))
+        .route("/canteens/:canteen_id", put(admin::rename_canteen))
+        .route("/canteens/:canteen_id/merge", post(admin::merge_canteen))
+        .route(
+            "/canteens/:canteen_id/days/:date",
+            delete(admin::clear_canteen_day),
+        )
+        .route("/webhooks", get(admin::list_webhooks))
+        .layer(middleware::from_fn_with_state(
+            admin_tokens.clone(),
+            admin::require_admin_token,
+        ));
 
     Router::new()
+        .merge(v1)
+        .nest("/v2", v2)
+        .nest("/admin", admin)
         .route("/", get(|| async { "API is reachable".into_response() }))
+        .route("/health", get(health::get_health))
+        .route("/ready", get(health::get_ready))
+        .route("/status", get(health::get_status))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/menu", get(menu::get_menu_index))
+        .route(
+            "/webhooks",
+            post(move |headers, json| webhooks::create_webhook(headers, json, admin_tokens)),
+        )
+        .route(
+            "/webhooks/:webhook_id",
+            get(webhooks::get_webhook).delete(webhooks::delete_webhook),
+        )
+        .route(
+            "/webhooks/:webhook_id/enable",
+            post(webhooks::enable_webhook),
+        )
+        .route("/ws", get(move |ws| ws::ws_handler(ws, ws_tx)))
         .route(
             "/today_updated_ws",
-            get(move |ws| services::ws_handler_today_upd_id(ws, today_updated_id_tx)),
+            get(move |ws, scope| services::ws_handler_today_upd_id(ws, scope, today_updated_id_tx)),
         )
         .route(
             "/today_updated_diff_ws",
-            get(move |ws| services::ws_handler_today_upd_diff(ws, today_updated_diff_tx)),
+            get(move |ws, scope| {
+                services::ws_handler_today_upd_diff(ws, scope, today_updated_diff_tx)
+            }),
         )
-        .route("/canteens", get(services::get_canteens))
-        .route("/canteens/:canteen_id", get(services::get_canteen_meta))
         .route(
-            "/canteens/:canteen_id/days",
-            get(services::get_canteen_available_days),
+            "/today_updated_sse",
+            get(move |headers, canteens, query| {
+                sse::today_updated_sse(headers, canteens, query, today_updated_sse_tx)
+            }),
         )
         .route(
-            "/canteens/:canteen_id/days/:date",
-            get(services::get_meals_of_day),
+            "/canteens/:canteen_id/menu.ics",
+            get(ical::get_canteen_ical),
+        )
+        .route(
+            "/canteens/:canteen_id/feed.atom",
+            get(feeds::get_canteen_atom),
+        )
+        .route(
+            "/canteens/:canteen_id/feed.rss",
+            get(feeds::get_canteen_rss),
+        )
+        .route(
+            "/graphql",
+            get(graphql::graphiql).post_service(GraphQL::new(schema.clone())),
         )
+        .route_service("/graphql/ws", GraphQLSubscription::new(schema))
         .route(
             "/openmensacanteens",
             get(openmensa_funcs::get_openmensa_canteens),
         )
+        .fallback(route_not_found)
+        .layer(CatchPanicLayer::custom(panic_to_response))
+        .layer(overload_protection)
+        .layer(middleware::from_fn_with_state(
+            rate_limit::RateLimiter::from_env(),
+            rate_limit::rate_limit,
+        ))
+        .layer(middleware::from_fn(metrics::track_http_metrics))
         .layer(cors)
 }
+
+async fn add_deprecation_headers(req: Request, next: Next) -> Response {
+    let successor = format!("</v2{}>; rel=\"successor-version\"", req.uri().path());
+    let mut response = next.run(req).await;
+
+    let headers = response.headers_mut();
+    headers.insert(
+        DEPRECATION_HEADER,
+        HeaderValue::from_static(V1_DEPRECATED_AT),
+    );
+    if let Some(sunset) = V1_SUNSET.as_ref() {
+        headers.insert(SUNSET_HEADER, sunset.clone());
+    }
+    if let Ok(successor) = HeaderValue::from_str(&successor) {
+        headers.insert(LINK, successor);
+    }
+
+    response
+}
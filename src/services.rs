@@ -1,66 +1,181 @@
+use std::collections::BTreeMap;
+
 use axum::{
-    extract::{ws::WebSocket, Path, WebSocketUpgrade},
-    response::IntoResponse,
-    Json,
+    extract::{
+        ws::{close_code, Message, WebSocket},
+        WebSocketUpgrade,
+    },
+    response::{IntoResponse, Response},
 };
-use chrono::NaiveDate;
-use http::StatusCode;
-use tokio::sync::broadcast;
+use chrono::{Duration, NaiveDate};
+use http::HeaderMap;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 use crate::{
-    constants::CANTEEN_MAP,
-    db_operations::{get_meals_from_db, list_available_days_db},
-    types::{Canteen, CanteenMealDiff, MealGroup, ResponseError},
+    constants::{CANTEEN_MAP, MAX_DATE_RANGE_DAYS},
+    date_funcs::{is_date_alias, resolve_date, RESOLVED_DATE_HEADER},
+    db_operations::{
+        get_last_changed_db, get_meals_from_db, get_stored_days_db, get_stored_days_of_date_db,
+        json_to_meal, list_available_days_db, list_scraped_days_in_range_db,
+    },
+    error::{ApiError, ApiPath, ApiQuery},
+    event_log::LogCursor,
+    http_cache::{cached_json, vary_accept},
+    meal_filter::MealFilter,
+    menu::{all_canteens_page, canteen_days_page, menu_response},
+    metrics::{WsClientGuard, BROADCAST_LAGGED},
+    render::negotiate_format,
+    snapshot::{snapshot_dates, take_snapshot},
+    stuwe_request_funcs::build_date_string,
+    types::{
+        Canteen, CanteenDay, CanteenFilterQuery, CanteenMealDiff, CanteenMealsDay, DateRangeQuery,
+        DayStatus, DiffFormat, FormatQuery, HasChanges, MealFilterQuery, MealPatch, ResponseFormat,
+        StoredDay, WsQuery,
+    },
+    ws::{close, close_timed_out, ping, Keepalive, EVENTS_LOST_CLOSE_CODE},
 };
 
 // handler to upgrade http to websocket connection (WS only sends IDs)
 pub async fn ws_handler_today_upd_id(
     ws: WebSocketUpgrade,
+    ApiQuery(ws_query): ApiQuery<WsQuery>,
     today_updated_tx: broadcast::Sender<CanteenMealDiff>,
-) -> impl IntoResponse {
+) -> Result<Response, ApiError> {
     // Upgrades the connection to a WebSocket and calls the `websocket` function to handle the connection.
+    if ws_query.snapshot || ws_query.format != DiffFormat::Diff {
+        return Err(ApiError::InvalidRequest(
+            "snapshot and format are only available on /today_updated_diff_ws".to_string(),
+        ));
+    }
     log::info!("WebSocket client connected (ID only)");
-    ws.on_upgrade(|socket| websocket_today_upd(socket, today_updated_tx, false))
+    Ok(ws.on_upgrade(move |socket| websocket_today_upd(socket, today_updated_tx, false, ws_query)))
 }
 
 // http → websocket (WS sends diff)
 pub async fn ws_handler_today_upd_diff(
     ws: WebSocketUpgrade,
+    ApiQuery(ws_query): ApiQuery<WsQuery>,
     today_updated_tx: broadcast::Sender<CanteenMealDiff>,
 ) -> impl IntoResponse {
     // Upgrades the connection to a WebSocket and calls the `websocket` function to handle the connection.
     log::info!("WebSocket client connected (ID+diff)");
-    ws.on_upgrade(|socket| websocket_today_upd(socket, today_updated_tx, true))
+    ws.on_upgrade(move |socket| websocket_today_upd(socket, today_updated_tx, true, ws_query))
 }
 
 // actual websocket handler after http->ws upgrade
-// broadcasts either only the mensa id or a more complex diff whenever its today's menu is updated
+// broadcasts either only the mensa id or a more complex diff whenever its today's menu
+// (or with UpdateScope::Upcoming, any upcoming menu) is updated.
+// with `since`, the kept diffs after that sequence number are replayed first,
+// with `snapshot`, the stored meals are sent first as {"snapshot": {"seq": .., "days": [..]}},
+// with `format=patch`, JSON Patches of the changed days are sent instead of diffs
 pub async fn websocket_today_upd(
     mut socket: WebSocket,
     today_updated_tx: broadcast::Sender<CanteenMealDiff>,
     send_diff: bool,
+    ws_query: WsQuery,
 ) {
     // each websocket instance has its own receiver
     let mut rx = today_updated_tx.subscribe();
+    let channel = if send_diff {
+        "today_updated_diff"
+    } else {
+        "today_updated_id"
+    };
+    let _client_guard = WsClientGuard::new(channel);
+    let scope = ws_query.scope.unwrap_or_default();
+    let mut cursor = LogCursor::new(ws_query.since);
+    let mut keepalive = Keepalive::start();
 
-    while let Ok(msg) = rx.recv().await {
-        let msg = if send_diff {
-            serde_json::to_string(&msg).unwrap()
-        } else {
-            msg.canteen_id.to_string()
+    if ws_query.snapshot {
+        let snapshot = match snapshot_dates(scope, None) {
+            Ok(dates) => take_snapshot(&mut cursor, &dates, None).await,
+            Err(e) => Err(e),
         };
-        if socket
-            .send(axum::extract::ws::Message::Text(msg))
-            .await
-            .is_err()
+        let msg = match snapshot {
+            Ok((snapshot, _)) => {
+                serde_json::to_string(&BTreeMap::from([("snapshot", snapshot)])).unwrap()
+            }
+            Err(e) => {
+                log::error!("Taking a snapshot failed: {}", e);
+                close(socket, close_code::ERROR, "snapshot failed").await;
+                return;
+            }
+        };
+        if socket.send(Message::Text(msg)).await.is_err() {
+            return;
+        }
+    }
+
+    // a reconnecting client continues where it left off
+    let (mut diffs, complete) = cursor.catch_up();
+    if !complete {
+        log::info!("WebSocket client resumed from an unknown sequence number");
+        close(socket, EVENTS_LOST_CLOSE_CODE, "events lost").await;
+        return;
+    }
+
+    loop {
+        // revisions without meal changes are only sent as patches
+        let patches = send_diff && ws_query.format == DiffFormat::Patch;
+        for diff in diffs
+            .drain(..)
+            .filter(|diff| scope.includes(diff) && (patches || diff.has_changes()))
         {
-            // client has disconnected
-            break;
+            let msg = match (send_diff, ws_query.format) {
+                (false, _) => diff.canteen_id.to_string(),
+                (true, DiffFormat::Diff) => serde_json::to_string(&diff).unwrap(),
+                (true, DiffFormat::Patch) => {
+                    serde_json::to_string(&MealPatch::from(&diff)).unwrap()
+                }
+            };
+            if socket.send(Message::Text(msg)).await.is_err() {
+                // client has disconnected
+                return;
+            }
+        }
+
+        tokio::select! {
+            msg = socket.recv() => {
+                keepalive.seen();
+                match msg {
+                    // the close frame is answered by the next read, which then ends the stream
+                    Some(Ok(Message::Close(_))) => {}
+                    // the protocol is send-only, anything else just keeps the connection alive
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break,
+                }
+            },
+            diff = rx.recv() => match diff {
+                Ok(diff) => diffs.extend(cursor.advance(diff)),
+                // slow clients get the missed diffs from the event log
+                Err(RecvError::Lagged(missed)) => {
+                    BROADCAST_LAGGED
+                        .with_label_values(&[channel])
+                        .inc_by(missed);
+                    let (missed_diffs, complete) = cursor.catch_up();
+                    if !complete {
+                        log::warn!("WebSocket client lagged behind the event log, diffs were lost");
+                        close(socket, EVENTS_LOST_CLOSE_CODE, "events lost").await;
+                        return;
+                    }
+                    diffs = missed_diffs;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            alive = keepalive.tick() => {
+                if !alive {
+                    close_timed_out(socket).await;
+                    return;
+                }
+                if ping(&mut socket).await.is_err() {
+                    break;
+                }
+            },
         }
     }
 }
 
-pub async fn get_canteens() -> Json<Vec<Canteen>> {
+pub async fn get_canteens(headers: HeaderMap) -> Response {
     let mut canteen_list: Vec<Canteen> = Vec::new();
 
     for (id, name) in CANTEEN_MAP.read().unwrap().iter() {
@@ -70,48 +185,278 @@ pub async fn get_canteens() -> Json<Vec<Canteen>> {
         });
     }
 
-    Json(canteen_list)
+    cached_json(&headers, &canteen_list, None)
 }
 
-pub async fn get_canteen_meta(Path(canteen_id): Path<u32>) -> Result<Json<Canteen>, StatusCode> {
-    match CANTEEN_MAP.read().unwrap().get(&canteen_id) {
-        Some(name) => Ok(Json(Canteen {
+pub async fn get_canteen_meta(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    let canteen = match CANTEEN_MAP.read().unwrap().get(&canteen_id) {
+        Some(name) => Canteen {
             id: canteen_id,
             name: name.clone(),
-        })),
-        None => Err(StatusCode::NOT_FOUND),
-    }
+        },
+        None => return Err(ApiError::CanteenNotFound),
+    };
+
+    Ok(cached_json(&headers, &canteen, None))
 }
 
 pub async fn get_canteen_available_days(
-    Path(canteen_id): Path<u32>,
-) -> Result<Json<Vec<String>>, StatusCode> {
-    if get_canteen_meta(Path(canteen_id)).await.is_err() {
-        return Err(StatusCode::NOT_FOUND);
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    if CANTEEN_MAP.read().unwrap().get(&canteen_id).is_none() {
+        return Err(ApiError::CanteenNotFound);
     };
 
-    let available_days = list_available_days_db(canteen_id).unwrap_or_default();
-    Ok(Json(available_days))
+    let available_days = list_available_days_db(canteen_id)?;
+    let last_changed = match (available_days.iter().min(), available_days.iter().max()) {
+        (Some(first), Some(last)) => get_last_changed_db(Some(canteen_id), first, last)?,
+        _ => None,
+    };
+
+    Ok(cached_json(&headers, &available_days, last_changed))
 }
 
 pub async fn get_meals_of_day(
-    Path((canteen_id, date)): Path<(u32, String)>,
-) -> Result<Json<Vec<MealGroup>>, ResponseError> {
-    let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d");
-    match date {
-        Err(_) => Err(ResponseError {
-            message: "Invalid date format".to_string(),
-            status_code: StatusCode::BAD_REQUEST,
-        }),
-        Ok(date) => {
-            if CANTEEN_MAP.read().unwrap().get(&canteen_id).is_none() {
-                return Err(ResponseError {
-                    message: "Canteen not found".to_string(),
-                    status_code: StatusCode::NOT_FOUND,
-                });
-            }
-            let day_meals = get_meals_from_db(canteen_id, date).await.unwrap();
-            Ok(Json(day_meals))
-        }
+    headers: HeaderMap,
+    ApiPath((canteen_id, date)): ApiPath<(u32, String)>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+
+    if CANTEEN_MAP.read().unwrap().get(&canteen_id).is_none() {
+        return Err(ApiError::CanteenNotFound);
     }
+
+    let is_alias = is_date_alias(&date);
+    let date = resolve_date(&date, Some(canteen_id))?;
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let days = load_canteen_days(canteen_id, date, date, &filter).await?;
+        let last_changed = days
+            .values()
+            .filter_map(|day| day.last_changed)
+            .max()
+            .filter(|_| !is_alias);
+        let page = canteen_days_page(&headers, "", canteen_id, days);
+        return Ok((
+            [(RESOLVED_DATE_HEADER, build_date_string(date))],
+            menu_response(&headers, format, &page, last_changed),
+        )
+            .into_response());
+    }
+
+    let day_meals = filter.apply(get_meals_from_db(canteen_id, date).await?);
+    let date_str = build_date_string(date);
+    let last_changed =
+        get_last_changed_db(Some(canteen_id), &date_str, &date_str)?.filter(|_| !is_alias);
+
+    Ok((
+        [(RESOLVED_DATE_HEADER, date_str)],
+        vary_accept(cached_json(&headers, &day_meals, last_changed)),
+    )
+        .into_response())
+}
+
+pub async fn get_meals_of_range(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+    ApiQuery(range): ApiQuery<DateRangeQuery>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+    let is_alias = is_date_alias(&range.from) || is_date_alias(&range.to);
+    let from = resolve_date(&range.from, Some(canteen_id))?;
+    let to = resolve_date(&range.to, Some(canteen_id))?;
+
+    let days = load_canteen_days(canteen_id, from, to, &filter).await?;
+    let last_changed = get_last_changed_db(
+        Some(canteen_id),
+        &build_date_string(from),
+        &build_date_string(to),
+    )?
+    .filter(|_| !is_alias);
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let page = canteen_days_page(&headers, "", canteen_id, days);
+        return Ok(menu_response(&headers, format, &page, last_changed));
+    }
+
+    Ok(vary_accept(cached_json(&headers, &days, last_changed)))
+}
+
+// loads every day within [from, to] (limited to MAX_DATE_RANGE_DAYS) with its status
+pub async fn load_canteen_days(
+    canteen_id: u32,
+    from: NaiveDate,
+    to: NaiveDate,
+    filter: &MealFilter,
+) -> Result<BTreeMap<String, CanteenDay>, ApiError> {
+    if to < from {
+        return Err(ApiError::InvalidRange(
+            "'to' must not be before 'from'".to_string(),
+        ));
+    }
+    if (to - from).num_days() >= MAX_DATE_RANGE_DAYS {
+        return Err(ApiError::InvalidRange(format!(
+            "Date range must not exceed {} days",
+            MAX_DATE_RANGE_DAYS
+        )));
+    }
+
+    if CANTEEN_MAP.read().unwrap().get(&canteen_id).is_none() {
+        return Err(ApiError::CanteenNotFound);
+    }
+
+    let (from_str, to_str) = (build_date_string(from), build_date_string(to));
+    let mut stored_days: BTreeMap<String, StoredDay> =
+        get_stored_days_db(canteen_id, &from_str, &to_str)?
+            .into_iter()
+            .map(|stored_day| (stored_day.date.clone(), stored_day))
+            .collect();
+    let scraped_days = list_scraped_days_in_range_db(&from_str, &to_str)?;
+
+    let mut days = BTreeMap::new();
+    let mut date = from;
+    while date <= to {
+        let date_str = build_date_string(date);
+        let stored_day = stored_days.remove(&date_str);
+        let day =
+            stored_day_to_canteen_day(stored_day, scraped_days.contains(&date_str), filter).await?;
+
+        days.insert(date_str, day);
+        date += Duration::days(1);
+    }
+
+    Ok(days)
+}
+
+pub async fn get_all_meals_of_day(
+    headers: HeaderMap,
+    ApiPath(date): ApiPath<String>,
+    ApiQuery(filter): ApiQuery<CanteenFilterQuery>,
+    ApiQuery(meal_filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let meal_filter = MealFilter::try_from(meal_filter)?;
+    // 'next_open' here means the next day any canteen is open
+    let is_alias = is_date_alias(&date);
+    let date = resolve_date(&date, None)?;
+    let canteen_ids = parse_canteen_list(&filter)?;
+
+    let all_canteens = load_all_canteens_day(date, canteen_ids, &meal_filter).await?;
+    let last_changed = all_canteens
+        .iter()
+        .filter_map(|(_, day)| day.last_changed)
+        .max()
+        .filter(|_| !is_alias);
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let page = all_canteens_page(&headers, "", date, all_canteens);
+        return Ok((
+            [(RESOLVED_DATE_HEADER, build_date_string(date))],
+            menu_response(&headers, format, &page, last_changed),
+        )
+            .into_response());
+    }
+
+    let all_canteens: Vec<CanteenMealsDay> = all_canteens
+        .into_iter()
+        .map(|(canteen_id, day)| CanteenMealsDay {
+            canteen_id,
+            meal_groups: day.meal_groups,
+        })
+        .collect();
+
+    Ok((
+        [(RESOLVED_DATE_HEADER, build_date_string(date))],
+        vary_accept(cached_json(&headers, &all_canteens, last_changed)),
+    )
+        .into_response())
+}
+
+// returns the requested canteen IDs, or all IDs if none were requested.
+// unknown canteen IDs are skipped, so clients with outdated lists still get the rest
+pub fn parse_canteen_list(filter: &CanteenFilterQuery) -> Result<Vec<u32>, ApiError> {
+    let requested_ids = parse_canteen_ids(filter)?;
+
+    let canteen_map = CANTEEN_MAP.read().unwrap();
+    Ok(match requested_ids {
+        Some(ids) => ids
+            .into_iter()
+            .filter(|id| canteen_map.contains_key(id))
+            .collect(),
+        None => canteen_map.keys().copied().collect(),
+    })
+}
+
+// returns the requested canteen IDs as given, or None if none were requested
+pub fn parse_canteen_ids(filter: &CanteenFilterQuery) -> Result<Option<Vec<u32>>, ApiError> {
+    match filter.canteens.as_deref() {
+        None | Some("") => Ok(None),
+        Some(list) => list
+            .split(',')
+            .map(|id| id.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+            .map_err(|_| ApiError::InvalidCanteenList),
+    }
+}
+
+// loads the given canteens' meals of a single day with their status
+pub async fn load_all_canteens_day(
+    date: NaiveDate,
+    canteen_ids: Vec<u32>,
+    filter: &MealFilter,
+) -> Result<Vec<(u32, CanteenDay)>, ApiError> {
+    let mut stored_canteens = get_stored_days_of_date_db(&build_date_string(date))?;
+    let date_was_scraped = !stored_canteens.is_empty();
+
+    let mut all_canteens = vec![];
+    for canteen_id in canteen_ids {
+        let stored_day = stored_canteens.remove(&canteen_id);
+        all_canteens.push((
+            canteen_id,
+            stored_day_to_canteen_day(stored_day, date_was_scraped, filter).await?,
+        ));
+    }
+
+    Ok(all_canteens)
+}
+
+async fn stored_day_to_canteen_day(
+    stored_day: Option<StoredDay>,
+    date_was_scraped: bool,
+    filter: &MealFilter,
+) -> Result<CanteenDay, ApiError> {
+    let (meal_groups, last_changed) = match stored_day {
+        Some(stored_day) => (
+            json_to_meal(&stored_day.json_text).await?,
+            stored_day.last_changed,
+        ),
+        None => (vec![], None),
+    };
+
+    // status describes the canteen, so it is determined before filtering
+    let status = if !meal_groups.is_empty() {
+        DayStatus::Open
+    } else if date_was_scraped {
+        DayStatus::Closed
+    } else {
+        DayStatus::Unknown
+    };
+
+    Ok(CanteenDay {
+        status,
+        meal_groups: filter.apply(meal_groups),
+        last_changed,
+    })
 }
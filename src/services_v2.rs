@@ -0,0 +1,234 @@
+use axum::response::Response;
+use chrono::NaiveDate;
+use http::HeaderMap;
+
+use crate::{
+    constants::{CANTEEN_MAP, SCRAPE_STATUS},
+    date_funcs::{is_date_alias, resolve_date},
+    db_operations::get_stored_days_db,
+    error::{ApiError, ApiPath, ApiQuery},
+    http_cache::{cached_json, vary_accept},
+    meal_filter::MealFilter,
+    menu::{all_canteens_page, canteen_days_page, menu_response},
+    render::negotiate_format,
+    services::{load_all_canteens_day, load_canteen_days, parse_canteen_list},
+    stuwe_request_funcs::build_date_string,
+    types::{
+        Canteen, CanteenDay, CanteenFilterQuery, DateRangeQuery, DayStatus, FormatQuery,
+        MealFilterQuery, ResponseFormat, V2Day, V2DaySummary, V2Response,
+    },
+};
+
+pub async fn get_canteens(headers: HeaderMap) -> Response {
+    let canteens: Vec<Canteen> = CANTEEN_MAP
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(id, name)| Canteen {
+            id: *id,
+            name: name.clone(),
+        })
+        .collect();
+
+    cached_json(&headers, &envelope(None, canteens), None)
+}
+
+pub async fn get_canteen(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    let canteen = Canteen {
+        id: canteen_id,
+        name: canteen_name(canteen_id)?,
+    };
+
+    Ok(cached_json(
+        &headers,
+        &envelope(Some(canteen_id), canteen),
+        None,
+    ))
+}
+
+pub async fn get_canteen_days(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    canteen_name(canteen_id)?;
+
+    let days: Vec<V2DaySummary> = get_stored_days_db(canteen_id, "0000-01-01", "9999-12-31")?
+        .into_iter()
+        .map(|stored_day| V2DaySummary {
+            status: if stored_day.json_text == "[]" {
+                DayStatus::Closed
+            } else {
+                DayStatus::Open
+            },
+            date: stored_day.date,
+            last_changed_at: stored_day.last_changed,
+        })
+        .collect();
+    let last_changed = days.iter().filter_map(|day| day.last_changed_at).max();
+
+    Ok(cached_json(
+        &headers,
+        &envelope(Some(canteen_id), days),
+        last_changed,
+    ))
+}
+
+pub async fn get_meals_of_day(
+    headers: HeaderMap,
+    ApiPath((canteen_id, date)): ApiPath<(u32, String)>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+    canteen_name(canteen_id)?;
+    let is_alias = is_date_alias(&date);
+    let date = resolve_date(&date, Some(canteen_id))?;
+
+    let days = load_canteen_days(canteen_id, date, date, &filter).await?;
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let last_changed = days
+            .values()
+            .filter_map(|day| day.last_changed)
+            .max()
+            .filter(|_| !is_alias);
+        let page = canteen_days_page(&headers, "/v2", canteen_id, days);
+        return Ok(menu_response(&headers, format, &page, last_changed));
+    }
+
+    let Some(day) = days.into_values().next() else {
+        return Err(ApiError::Internal("Requested day is missing".to_string()));
+    };
+    let last_changed = day.last_changed.filter(|_| !is_alias);
+
+    // a single day already carries all envelope fields
+    Ok(vary_accept(cached_json(
+        &headers,
+        &to_v2_day(canteen_id, date, day),
+        last_changed,
+    )))
+}
+
+pub async fn get_meals_of_range(
+    headers: HeaderMap,
+    ApiPath(canteen_id): ApiPath<u32>,
+    ApiQuery(range): ApiQuery<DateRangeQuery>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+    let is_alias = is_date_alias(&range.from) || is_date_alias(&range.to);
+    let from = resolve_date(&range.from, Some(canteen_id))?;
+    let to = resolve_date(&range.to, Some(canteen_id))?;
+
+    let days = load_canteen_days(canteen_id, from, to, &filter).await?;
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let last_changed = days
+            .values()
+            .filter_map(|day| day.last_changed)
+            .max()
+            .filter(|_| !is_alias);
+        let page = canteen_days_page(&headers, "/v2", canteen_id, days);
+        return Ok(menu_response(&headers, format, &page, last_changed));
+    }
+
+    let days: Vec<V2Day> = days
+        .into_iter()
+        .filter_map(|(date, day)| {
+            let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+            Some(to_v2_day(canteen_id, date, day))
+        })
+        .collect();
+    let last_changed = days
+        .iter()
+        .filter_map(|day| day.last_changed_at)
+        .max()
+        .filter(|_| !is_alias);
+
+    let mut response = envelope(Some(canteen_id), days);
+    response.from = Some(build_date_string(from));
+    response.to = Some(build_date_string(to));
+
+    Ok(vary_accept(cached_json(&headers, &response, last_changed)))
+}
+
+pub async fn get_all_meals_of_day(
+    headers: HeaderMap,
+    ApiPath(date): ApiPath<String>,
+    ApiQuery(canteen_filter): ApiQuery<CanteenFilterQuery>,
+    ApiQuery(filter): ApiQuery<MealFilterQuery>,
+    ApiQuery(format_query): ApiQuery<FormatQuery>,
+) -> Result<Response, ApiError> {
+    let filter = MealFilter::try_from(filter)?;
+    let is_alias = is_date_alias(&date);
+    let date = resolve_date(&date, None)?;
+    let canteen_ids = parse_canteen_list(&canteen_filter)?;
+
+    let days = load_all_canteens_day(date, canteen_ids, &filter).await?;
+
+    let format = negotiate_format(&headers, format_query.format);
+    if format != ResponseFormat::Json {
+        let last_changed = days
+            .iter()
+            .filter_map(|(_, day)| day.last_changed)
+            .max()
+            .filter(|_| !is_alias);
+        let page = all_canteens_page(&headers, "/v2", date, days);
+        return Ok(menu_response(&headers, format, &page, last_changed));
+    }
+
+    let days: Vec<V2Day> = days
+        .into_iter()
+        .map(|(canteen_id, day)| to_v2_day(canteen_id, date, day))
+        .collect();
+    let last_changed = days
+        .iter()
+        .filter_map(|day| day.last_changed_at)
+        .max()
+        .filter(|_| !is_alias);
+
+    let mut response = envelope(None, days);
+    response.date = Some(build_date_string(date));
+
+    Ok(vary_accept(cached_json(&headers, &response, last_changed)))
+}
+
+fn envelope<T: serde::Serialize>(canteen_id: Option<u32>, data: T) -> V2Response<T> {
+    V2Response {
+        canteen_id,
+        date: None,
+        from: None,
+        to: None,
+        data,
+    }
+}
+
+fn canteen_name(canteen_id: u32) -> Result<String, ApiError> {
+    CANTEEN_MAP
+        .read()
+        .unwrap()
+        .get(&canteen_id)
+        .cloned()
+        .ok_or(ApiError::CanteenNotFound)
+}
+
+fn to_v2_day(canteen_id: u32, date: NaiveDate, day: CanteenDay) -> V2Day {
+    V2Day {
+        canteen_id,
+        date: build_date_string(date),
+        status: day.status,
+        fetched_at: SCRAPE_STATUS
+            .read()
+            .unwrap()
+            .get(&date)
+            .and_then(|status| status.last_success),
+        last_changed_at: day.last_changed,
+        meal_groups: day.meal_groups.into_iter().map(Into::into).collect(),
+    }
+}
@@ -0,0 +1,60 @@
+use crate::{
+    constants::CACHE_UPDATE_LOCK,
+    date_funcs::canteen_today,
+    db_operations::{get_stored_days_of_date_db, json_to_meal, list_scraped_days_in_range_db},
+    error::ApiResult,
+    event_log::LogCursor,
+    stuwe_request_funcs::build_date_string,
+    types::{CanteenMealDiff, MealsSnapshot, SnapshotDay, UpdateScope},
+};
+
+// dates covered by a snapshot: the given ones (as far as the scope includes them),
+// otherwise today or every stored date from today on
+pub fn snapshot_dates(scope: UpdateScope, dates: Option<&[String]>) -> ApiResult<Vec<String>> {
+    let today = build_date_string(canteen_today());
+    Ok(match (scope, dates) {
+        (UpdateScope::Today, Some(dates)) => dates
+            .iter()
+            .filter(|date| **date == today)
+            .cloned()
+            .collect(),
+        (UpdateScope::Upcoming, Some(dates)) => dates.to_vec(),
+        (UpdateScope::Today, None) => vec![today],
+        (UpdateScope::Upcoming, None) => list_scraped_days_in_range_db(&today, "9999-12-31")?
+            .into_iter()
+            .collect(),
+    })
+}
+
+// stored meals of the given dates and canteens (all if None), taken while no cache update
+// runs. the cursor is moved to the snapshot's version, so only later diffs are delivered.
+// the diffs it skips on the way are returned for subscriptions that existed before
+pub async fn take_snapshot(
+    cursor: &mut LogCursor,
+    dates: &[String],
+    canteens: Option<&[u32]>,
+) -> ApiResult<(MealsSnapshot, Vec<CanteenMealDiff>)> {
+    let _update_guard = CACHE_UPDATE_LOCK.read().await;
+
+    let mut days = Vec::new();
+    for date in dates {
+        for (canteen_id, stored_day) in get_stored_days_of_date_db(date)? {
+            if canteens.is_some_and(|canteens| !canteens.contains(&canteen_id)) {
+                continue;
+            }
+            days.push(SnapshotDay {
+                canteen_id,
+                date: stored_day.date,
+                meal_groups: json_to_meal(&stored_day.json_text).await?,
+            });
+        }
+    }
+
+    // nothing is recorded while the lock is held, so the cursor ends at the snapshot's version
+    let (skipped, _) = cursor.catch_up();
+    let snapshot = MealsSnapshot {
+        seq: cursor.last_seq(),
+        days,
+    };
+    Ok((snapshot, skipped))
+}
@@ -0,0 +1,113 @@
+use std::convert::Infallible;
+
+use axum::response::{
+    sse::{Event, KeepAlive, Sse},
+    IntoResponse, Response,
+};
+use http::HeaderMap;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_stream::StreamExt;
+
+use crate::{
+    error::{ApiError, ApiQuery},
+    event_log::{self, LogCursor, LogEvent},
+    metrics::WsClientGuard,
+    services::parse_canteen_ids,
+    types::{CanteenFilterQuery, CanteenMealDiff, HasChanges, MealPatch, UpdateScope},
+};
+
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SsePayload {
+    // only the canteen ID, like /today_updated_ws
+    #[default]
+    Id,
+    // the whole diff, like /today_updated_diff_ws
+    Diff,
+    // a JSON Patch of the day's meal groups, like /today_updated_diff_ws?format=patch
+    Patch,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SseQuery {
+    pub payload: Option<SsePayload>,
+    pub scope: Option<UpdateScope>,
+}
+
+// today-updated (or with scope=upcoming, any upcoming day) notifications as Server-Sent Events. the event ID is the diff's sequence number,
+// so reconnecting clients (Last-Event-ID) get the diffs they missed, as long as they are still logged
+pub async fn today_updated_sse(
+    headers: HeaderMap,
+    ApiQuery(canteen_filter): ApiQuery<CanteenFilterQuery>,
+    ApiQuery(query): ApiQuery<SseQuery>,
+    today_updated_tx: broadcast::Sender<CanteenMealDiff>,
+) -> Result<Response, ApiError> {
+    let canteen_ids = parse_canteen_ids(&canteen_filter)?;
+    let payload = query.payload.unwrap_or_default();
+    let scope = query.scope.unwrap_or_default();
+    let last_event_id = match headers.get(LAST_EVENT_ID_HEADER) {
+        None => None,
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    ApiError::InvalidRequest("Last-Event-ID must be a sequence number".to_string())
+                })?,
+        ),
+    };
+
+    // subscribe before reading the log, so nothing is lost in between
+    let rx = today_updated_tx.subscribe();
+    let cursor = LogCursor::new(last_event_id);
+
+    log::info!("SSE client connected ({:?} payload)", payload);
+    let client_guard = WsClientGuard::new("sse");
+
+    let events = event_log::follow(rx, cursor, "sse")
+        .filter(move |event| {
+            let _ = &client_guard;
+            match event {
+                LogEvent::Diff(diff) => {
+                    // revisions without meal changes are only sent as patches
+                    (payload == SsePayload::Patch || diff.has_changes())
+                        && scope.includes(diff)
+                        && canteen_ids
+                            .as_ref()
+                            .is_none_or(|ids| ids.contains(&diff.canteen_id))
+                }
+                LogEvent::Lost { .. } => true,
+            }
+        })
+        .map(move |event| Ok::<_, Infallible>(to_event(&event, payload)));
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+// changes that can't be replayed anymore are reported as an events_lost event
+fn to_event(event: &LogEvent, payload: SsePayload) -> Event {
+    let diff = match event {
+        LogEvent::Diff(diff) => diff.as_ref(),
+        LogEvent::Lost { after_seq } => {
+            return Event::default()
+                .event("events_lost")
+                .data(json!({ "after_seq": after_seq }).to_string())
+        }
+    };
+    let data = match payload {
+        SsePayload::Id => diff.canteen_id.to_string(),
+        SsePayload::Diff => serde_json::to_string(diff).unwrap(),
+        SsePayload::Patch => serde_json::to_string(&MealPatch::from(diff)).unwrap(),
+    };
+
+    Event::default()
+        .id(diff.seq.to_string())
+        .event("today_updated")
+        .data(data)
+}
@@ -5,10 +5,17 @@ use scraper::{Element, ElementRef, Html, Selector};
 use std::collections::BTreeMap;
 use std::time::Instant;
 
-use crate::constants::CANTEEN_MAP_INV;
-use crate::db_operations::{add_canteen_id_db, get_jsonmeals_from_db, save_meal_to_db};
+use crate::constants::{CANTEEN_EVENTS, CANTEEN_MAP, CANTEEN_MAP_INV, SCRAPE_STATUS};
+use crate::db_operations::{
+    add_canteen_id_db, get_canteen_aliases_db, get_canteens_from_db, get_jsonmeals_from_db,
+    get_latest_revision_db, save_meal_to_db,
+};
+use crate::error::ApiResult;
+use crate::json_patch;
+use crate::metrics::{CORRUPT_STORED_MEALS, HTML_PARSE_DURATION, MEAL_UPDATES, SCRAPE_DURATION};
 use crate::types::{
-    CanteenMealDiff, CanteenMealsDay, HasChanges, MealGroup, MealVariation, SingleMeal,
+    Allergen, Canteen, CanteenEvent, CanteenMealDiff, CanteenMealsDay, HasChanges, ListChange,
+    MealChange, MealGroup, MealVariation, SingleMeal, ValueChange, VariationsChange,
 };
 
 pub async fn _run_benchmark() -> Result<()> {
@@ -40,17 +47,47 @@ pub async fn _run_benchmark() -> Result<()> {
     Ok(())
 }
 
-pub async fn parse_and_save_meals(day: NaiveDate) -> Result<Vec<CanteenMealDiff>> {
-    let mut today_changed_canteen_diffs = vec![];
+// a canteen's plan as found on the StuWe site, its name isn't resolved to a canteen id yet
+pub struct ScrapedCanteen {
+    name: String,
+    // id in the site's canteen list, only needed if the name is unknown
+    site_id: Option<u32>,
+    meal_groups: Vec<MealGroup>,
+}
 
-    let date_string = build_date_string(day);
+// downloads and parses the plans of one day, the stored meals and canteens aren't touched
+pub async fn fetch_meals(day: NaiveDate) -> Result<Vec<ScrapedCanteen>> {
+    let _timer = SCRAPE_DURATION.start_timer();
+
+    SCRAPE_STATUS
+        .write()
+        .unwrap()
+        .entry(day)
+        .or_default()
+        .last_attempt = Some(chrono::Utc::now());
 
     // getting data from server
-    let downloaded_html = reqwest_get_html_text(&date_string).await?;
+    let downloaded_html = reqwest_get_html_text(&build_date_string(day)).await?;
 
-    let all_canteen_singleday = extract_data_from_html(&downloaded_html).await?;
+    extract_data_from_html(&downloaded_html).await
+}
+
+// returns the diffs of all canteens whose stored plan of this day changed,
+// the caller has to hold CACHE_UPDATE_LOCK for writing until they are broadcasted
+pub async fn save_meals(
+    day: NaiveDate,
+    scraped_canteens: Vec<ScrapedCanteen>,
+) -> Result<Vec<CanteenMealDiff>> {
+    let mut changed_canteen_diffs = vec![];
+
+    let date_string = build_date_string(day);
+    let canteens_seen = scraped_canteens.len();
     // serialize downloaded meals
-    for canteen_meals_singleday in all_canteen_singleday {
+    for scraped_canteen in scraped_canteens {
+        let canteen_meals_singleday = CanteenMealsDay {
+            canteen_id: resolve_canteen_id(&scraped_canteen.name, scraped_canteen.site_id)?,
+            meal_groups: scraped_canteen.meal_groups,
+        };
         let downloaded_json_text =
             serde_json::to_string(&canteen_meals_singleday.meal_groups).unwrap();
         let db_json_text =
@@ -64,35 +101,93 @@ pub async fn parse_and_save_meals(day: NaiveDate) -> Result<Vec<CanteenMealDiff>
                 canteen_meals_singleday.canteen_id,
                 date_string
             );
+            // parsed once, for the diff and the JSON patch. a corrupt stored version is
+            // overwritten by the downloaded plan, as if there was none
+            let stored = db_json_text.as_deref().and_then(|text| {
+                let parsed = serde_json::from_str::<serde_json::Value>(text).and_then(|value| {
+                    let meal_groups = serde_json::from_value::<Vec<MealGroup>>(value.clone())?;
+                    Ok((value, meal_groups))
+                });
+                match parsed {
+                    Ok(stored) => Some(stored),
+                    Err(e) => {
+                        CORRUPT_STORED_MEALS.inc();
+                        log::error!(
+                            "Stored meals of canteen {} on {} are corrupt, replacing them: {}",
+                            canteen_meals_singleday.canteen_id,
+                            date_string,
+                            e
+                        );
+                        None
+                    }
+                }
+            });
+            let (old_value, old_meals) = match stored {
+                Some((old_value, old_mealgroups)) => (
+                    Some(old_value),
+                    Some(CanteenMealsDay {
+                        canteen_id: canteen_meals_singleday.canteen_id,
+                        meal_groups: old_mealgroups,
+                    }),
+                ),
+                None => (None, None),
+            };
+
+            let mut diff =
+                diff_canteen_meals(&date_string, old_meals.as_ref(), &canteen_meals_singleday);
+            if !diff.has_changes() && old_meals.is_some() {
+                log::warn!("DB != downloaded data, but diffing found nothing!");
+            }
+
+            diff.revision =
+                get_latest_revision_db(canteen_meals_singleday.canteen_id, &date_string)?
+                    .unwrap_or(0)
+                    + 1;
+            let new_value = serde_json::to_value(&canteen_meals_singleday.meal_groups).unwrap();
+            diff.patch = match old_value {
+                Some(old_value) => json_patch::diff(&old_value, &new_value),
+                None => json_patch::replace_all(&new_value),
+            };
+
+            // the diff is kept with the meals (e.g. for feeds), but only if there was a previous version
+            let diff_json_text = old_meals
+                .is_some()
+                .then(|| serde_json::to_string(&diff).unwrap());
+
             save_meal_to_db(
                 &date_string,
                 canteen_meals_singleday.canteen_id,
                 &downloaded_json_text,
+                diff_json_text.as_deref(),
+                diff.revision,
             )
             .await?;
+            MEAL_UPDATES.inc();
 
-            if day.weekday() == chrono::Local::now().weekday() {
-                let old_meals = db_json_text
-                    .map(|text| serde_json::from_str::<Vec<MealGroup>>(&text).unwrap())
-                    .map(|old_mealgroups| CanteenMealsDay {
-                        canteen_id: canteen_meals_singleday.canteen_id,
-                        meal_groups: old_mealgroups,
-                    });
-
-                let diff = diff_canteen_meals(old_meals.as_ref(), &canteen_meals_singleday);
-                if diff.has_changes() {
-                    today_changed_canteen_diffs.push(diff);
-                } else if old_meals.is_some() {
-                    log::warn!("DB != downloaded data, but diffing found nothing!");
-                }
-            }
+            // every stored revision is broadcast, so patch subscribers can follow all of them.
+            // revisions without meal changes (first versions, reordered meals) only go to them
+            changed_canteen_diffs.push(diff);
         }
     }
 
-    Ok(today_changed_canteen_diffs)
+    {
+        let mut scrape_status = SCRAPE_STATUS.write().unwrap();
+        let status = scrape_status.entry(day).or_default();
+        status.last_success = Some(chrono::Utc::now());
+        status.last_error = None;
+        status.canteens_seen = canteens_seen;
+    }
+
+    Ok(changed_canteen_diffs)
 }
 
+// name similarity (0..1) from which two meals count as the same meal
+const SAME_MEAL_SIMILARITY: f64 = 0.8;
+// lower bound if neither the price nor the (listed) ingredients changed
+const SAME_MEAL_SIMILARITY_SAME_DETAILS: f64 = 0.5;
+
 pub fn diff_canteen_meals(
+    date: &str,
     old_canteenmeals: Option<&CanteenMealsDay>,
     new_canteenmeals: &CanteenMealsDay,
 ) -> CanteenMealDiff {
@@ -100,143 +195,228 @@ pub fn diff_canteen_meals(
     let mut modified_meals: Vec<MealGroup> = vec![];
     let mut modified_meals_ignoring_allergens: Vec<MealGroup> = vec![];
     let mut removed_meals: Vec<MealGroup> = vec![];
+    let mut renamed_meals: Vec<MealGroup> = vec![];
+    let mut moved_meals: Vec<MealGroup> = vec![];
+    let mut meal_changes: Vec<MealChange> = vec![];
 
     if let Some(old_canteenmeals) = old_canteenmeals {
         assert_eq!(old_canteenmeals.canteen_id, new_canteenmeals.canteen_id);
+
+        // meals without a meal of the same name in the same category, with their category
+        let mut unmatched_new: Vec<(&str, &SingleMeal)> = vec![];
+        let mut unmatched_old: Vec<(&str, &SingleMeal)> = vec![];
+
         for new_mealgroup in &new_canteenmeals.meal_groups {
-            let equiv_old_mealgroups = old_canteenmeals
+            let equiv_old_mealgroup = old_canteenmeals
                 .meal_groups
                 .iter()
                 .find(|old_group| old_group.meal_type == new_mealgroup.meal_type);
-            if equiv_old_mealgroups.is_none() {
-                // new category → all submeals are new
-                new_meals.push(new_mealgroup.clone());
-            } else {
-                // find new submeals
-                let new_or_changed_submeals =
-                    new_mealgroup.sub_meals.iter().filter(|new_submeal| {
-                        equiv_old_mealgroups
-                            .unwrap()
-                            .sub_meals
-                            .iter()
-                            .all(|old_submeal| (old_submeal != *new_submeal))
-                    });
-
-                let new_or_changed_ignoring_allergens =
-                    new_mealgroup.sub_meals.iter().filter(|new_submeal| {
-                        equiv_old_mealgroups
-                            .unwrap()
-                            .sub_meals
-                            .iter()
-                            .all(|old_submeal| {
-                                old_submeal.name != *new_submeal.name
-                                    || old_submeal.additional_ingredients
-                                        != *new_submeal.additional_ingredients
-                                    || old_submeal.variations != new_submeal.variations
-                                    || old_submeal.price != *new_submeal.price
-                            })
-                    });
-
-                let (changed_submeals, new_submeals): (Vec<_>, Vec<_>) = new_or_changed_submeals
-                    .partition(|meal| {
-                        equiv_old_mealgroups
-                            .unwrap()
-                            .sub_meals
-                            .iter()
-                            .any(|old_submeal| old_submeal.name == meal.name)
-                    });
-
-                let changed_submeals_ignoring_allergens = new_or_changed_ignoring_allergens
-                    .filter(|meal| {
-                        equiv_old_mealgroups
-                            .unwrap()
-                            .sub_meals
-                            .iter()
-                            .any(|old_submeal| old_submeal.name == meal.name)
-                    })
-                    .collect::<Vec<_>>();
-
-                if !changed_submeals.is_empty() {
-                    modified_meals.push(MealGroup {
-                        meal_type: new_mealgroup.meal_type.clone(),
-                        sub_meals: changed_submeals.into_iter().cloned().collect(),
-                    });
-                }
-
-                if !changed_submeals_ignoring_allergens.is_empty() {
-                    modified_meals_ignoring_allergens.push(MealGroup {
-                        meal_type: new_mealgroup.meal_type.clone(),
-                        sub_meals: changed_submeals_ignoring_allergens
-                            .into_iter()
-                            .cloned()
-                            .collect(),
-                    });
+            for new_submeal in &new_mealgroup.sub_meals {
+                let old_submeal = equiv_old_mealgroup.and_then(|old_group| {
+                    old_group
+                        .sub_meals
+                        .iter()
+                        .find(|old_submeal| old_submeal.name == new_submeal.name)
+                });
+                let meal_type = &new_mealgroup.meal_type;
+                match old_submeal {
+                    Some(old_submeal) if old_submeal != new_submeal => {
+                        push_meal(&mut modified_meals, meal_type, new_submeal);
+                        if !equal_ignoring_allergens(old_submeal, new_submeal) {
+                            push_meal(
+                                &mut modified_meals_ignoring_allergens,
+                                meal_type,
+                                new_submeal,
+                            );
+                        }
+                        meal_changes.push(diff_single_meal(
+                            meal_type,
+                            meal_type,
+                            old_submeal,
+                            new_submeal,
+                        ));
+                    }
+                    Some(_) => {}
+                    None => unmatched_new.push((meal_type, new_submeal)),
                 }
+            }
+        }
 
-                if !new_submeals.is_empty() {
-                    new_meals.push(MealGroup {
-                        meal_type: new_mealgroup.meal_type.clone(),
-                        sub_meals: new_submeals.into_iter().cloned().collect(),
-                    });
+        for old_mealgroup in &old_canteenmeals.meal_groups {
+            let equiv_new_mealgroup = new_canteenmeals
+                .meal_groups
+                .iter()
+                .find(|new_group| new_group.meal_type == old_mealgroup.meal_type);
+            for old_submeal in &old_mealgroup.sub_meals {
+                if equiv_new_mealgroup.is_none_or(|new_group| {
+                    new_group
+                        .sub_meals
+                        .iter()
+                        .all(|new_submeal| new_submeal.name != old_submeal.name)
+                }) {
+                    unmatched_old.push((&old_mealgroup.meal_type, old_submeal));
                 }
+            }
+        }
 
-                // find removed submeals if the category already exists in old data
-                let removed_submeals: Vec<_> = equiv_old_mealgroups
-                    .unwrap()
-                    .sub_meals
-                    .iter()
-                    .filter(|old_submeal| {
-                        new_mealgroup
-                            .sub_meals
-                            .iter()
-                            .all(|new_submeal| new_submeal.name != old_submeal.name)
-                    })
-                    .cloned()
-                    .collect();
-
-                if !removed_submeals.is_empty() {
-                    removed_meals.push(MealGroup {
-                        meal_type: new_mealgroup.meal_type.clone(),
-                        sub_meals: removed_submeals,
-                    });
-                }
+        // similar meals are renamed within a category or moved to another one,
+        // instead of being reported as removed and new
+        let mut new_matched = vec![false; unmatched_new.len()];
+        let mut old_matched = vec![false; unmatched_old.len()];
+        for (new_idx, old_idx) in match_similar_meals(&unmatched_new, &unmatched_old) {
+            let (new_type, new_submeal) = unmatched_new[new_idx];
+            let (old_type, old_submeal) = unmatched_old[old_idx];
+            new_matched[new_idx] = true;
+            old_matched[old_idx] = true;
+
+            if new_type == old_type {
+                push_meal(&mut renamed_meals, new_type, new_submeal);
+            } else {
+                push_meal(&mut moved_meals, new_type, new_submeal);
             }
+            meal_changes.push(diff_single_meal(
+                old_type,
+                new_type,
+                old_submeal,
+                new_submeal,
+            ));
         }
 
-        // find removed categories
-        for old_meal_group in &old_canteenmeals.meal_groups {
-            if !new_canteenmeals
-                .meal_groups
-                .iter()
-                .any(|new_group| new_group.meal_type == old_meal_group.meal_type)
-            {
-                removed_meals.push(old_meal_group.clone());
+        for (matched, (meal_type, new_submeal)) in new_matched.into_iter().zip(unmatched_new) {
+            if !matched {
+                push_meal(&mut new_meals, meal_type, new_submeal);
+            }
+        }
+        for (matched, (meal_type, old_submeal)) in old_matched.into_iter().zip(unmatched_old) {
+            if !matched {
+                push_meal(&mut removed_meals, meal_type, old_submeal);
             }
         }
     }
 
+    let non_empty = |meal_groups: Vec<MealGroup>| (!meal_groups.is_empty()).then_some(meal_groups);
     CanteenMealDiff {
+        seq: 0,
         canteen_id: new_canteenmeals.canteen_id,
-        new_meals: if new_meals.is_empty() {
-            None
-        } else {
-            Some(new_meals)
-        },
-        modified_meals: if modified_meals.is_empty() {
-            None
-        } else {
-            Some(modified_meals)
-        },
-        modified_meals_ignoring_allergens: if modified_meals_ignoring_allergens.is_empty() {
-            None
-        } else {
-            Some(modified_meals_ignoring_allergens)
-        },
-        removed_meals: if removed_meals.is_empty() {
-            None
-        } else {
-            Some(removed_meals)
-        },
+        date: date.to_string(),
+        new_meals: non_empty(new_meals),
+        modified_meals: non_empty(modified_meals),
+        modified_meals_ignoring_allergens: non_empty(modified_meals_ignoring_allergens),
+        removed_meals: non_empty(removed_meals),
+        renamed_meals: non_empty(renamed_meals),
+        moved_meals: non_empty(moved_meals),
+        meal_changes: (!meal_changes.is_empty()).then_some(meal_changes),
+        revision: 0,
+        patch: vec![],
+    }
+}
+
+// adds a meal to its category within a list of changed meals
+fn push_meal(meal_groups: &mut Vec<MealGroup>, meal_type: &str, meal: &SingleMeal) {
+    match meal_groups
+        .iter_mut()
+        .find(|group| group.meal_type == meal_type)
+    {
+        Some(group) => group.sub_meals.push(meal.clone()),
+        None => meal_groups.push(MealGroup {
+            meal_type: meal_type.to_string(),
+            sub_meals: vec![meal.clone()],
+        }),
+    }
+}
+
+fn equal_ignoring_allergens(old: &SingleMeal, new: &SingleMeal) -> bool {
+    old.name == new.name
+        && old.additional_ingredients == new.additional_ingredients
+        && old.variations == new.variations
+        && old.price == new.price
+}
+
+// pairs of (new, old) indices of meals that are most likely the same meal, best matches first.
+// each meal is part of at most one pair, meals of the same category are preferred
+fn match_similar_meals(
+    new_meals: &[(&str, &SingleMeal)],
+    old_meals: &[(&str, &SingleMeal)],
+) -> Vec<(usize, usize)> {
+    let mut candidates = vec![];
+    for (new_idx, (new_type, new_meal)) in new_meals.iter().enumerate() {
+        for (old_idx, (old_type, old_meal)) in old_meals.iter().enumerate() {
+            let name_similarity = strsim::normalized_damerau_levenshtein(
+                &old_meal.name.to_lowercase(),
+                &new_meal.name.to_lowercase(),
+            );
+            // many meals share a price and list no ingredients, that alone doesn't make them similar
+            let same_details = old_meal.price == new_meal.price
+                && !old_meal.additional_ingredients.is_empty()
+                && old_meal.additional_ingredients == new_meal.additional_ingredients;
+            let threshold = if same_details {
+                SAME_MEAL_SIMILARITY_SAME_DETAILS
+            } else {
+                SAME_MEAL_SIMILARITY
+            };
+            if name_similarity < threshold {
+                continue;
+            }
+
+            let mut score = name_similarity;
+            if same_details {
+                score += 0.1;
+            }
+            if new_type == old_type {
+                score += 0.1;
+            }
+            candidates.push((score, new_idx, old_idx));
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut pairs: Vec<(usize, usize)> = vec![];
+    for (_, new_idx, old_idx) in candidates {
+        if pairs
+            .iter()
+            .all(|(paired_new, paired_old)| *paired_new != new_idx && *paired_old != old_idx)
+        {
+            pairs.push((new_idx, old_idx));
+        }
+    }
+    pairs
+}
+
+// old and new values of every field that differs between two versions of a meal
+fn diff_single_meal(
+    old_meal_type: &str,
+    new_meal_type: &str,
+    old: &SingleMeal,
+    new: &SingleMeal,
+) -> MealChange {
+    let value_change = |old: &str, new: &str| {
+        (old != new).then(|| ValueChange {
+            old: old.to_string(),
+            new: new.to_string(),
+        })
+    };
+    let allergen_labels = |meal: &SingleMeal| {
+        meal.allergen_list()
+            .iter()
+            .map(Allergen::label)
+            .collect::<Vec<_>>()
+    };
+
+    MealChange {
+        meal_type: new_meal_type.to_string(),
+        meal: new.name.clone(),
+        category: value_change(old_meal_type, new_meal_type),
+        name: value_change(&old.name, &new.name),
+        price: value_change(&old.price, &new.price),
+        ingredients: ListChange::between(
+            old.additional_ingredients.clone(),
+            new.additional_ingredients.clone(),
+        ),
+        allergens: ListChange::between(allergen_labels(old), allergen_labels(new)),
+        variations: (old.variations != new.variations).then(|| VariationsChange {
+            old: old.variations.clone().unwrap_or_default(),
+            new: new.variations.clone().unwrap_or_default(),
+        }),
     }
 }
 
@@ -260,7 +440,7 @@ async fn reqwest_get_html_text(date: &str) -> Result<String> {
     Ok(txt)
 }
 
-async fn extract_data_from_html(html_text: &str) -> Result<Vec<CanteenMealsDay>> {
+async fn extract_data_from_html(html_text: &str) -> Result<Vec<ScrapedCanteen>> {
     let mut all_data_for_day = vec![];
 
     let now = Instant::now();
@@ -288,33 +468,14 @@ async fn extract_data_from_html(html_text: &str) -> Result<Vec<CanteenMealsDay>>
                 .context("h3 without meal container")?,
         )?;
 
-        let canteen_map_inv_r = CANTEEN_MAP_INV.read().unwrap();
-        let canteen_id = canteen_map_inv_r.get(&canteen_name).copied().unwrap_or({
-            // drop here, otherwise drop would occur after the write lock (≙ dead lock)
-            drop(canteen_map_inv_r);
-            let extr_id = extract_canteenid(&document, &canteen_name)?;
-
-            if CANTEEN_MAP_INV
-                .write()
-                .unwrap()
-                .insert(canteen_name.clone(), extr_id)
-                // race conditions between writers and readers can cause two readers to think
-                // the value needs to be added (only writes lock exclusively)
-                .is_none()
-            {
-                log::info!("Adding new canteen to db: {}", canteen_name);
-                add_canteen_id_db(extr_id, &canteen_name)?;
-            };
-
-            extr_id
-        });
-
-        all_data_for_day.push(CanteenMealsDay {
-            canteen_id,
+        all_data_for_day.push(ScrapedCanteen {
+            site_id: extract_canteenid(&document, &canteen_name).ok(),
+            name: canteen_name,
             meal_groups: meals,
         });
     }
 
+    HTML_PARSE_DURATION.observe(now.elapsed().as_secs_f64());
     log::info!("HTML → Data: {:.2?}", now.elapsed());
     Ok(all_data_for_day)
 }
@@ -467,6 +628,38 @@ fn extract_mealgroup_from_htmlcontainer(meal_container: ElementRef<'_>) -> Resul
     Ok(v_meal_groups)
 }
 
+// unknown canteens are added with the id of the site's canteen list
+fn resolve_canteen_id(canteen_name: &str, site_id: Option<u32>) -> Result<u32> {
+    let canteen_map_inv_r = CANTEEN_MAP_INV.read().unwrap();
+    if let Some(canteen_id) = canteen_map_inv_r.get(canteen_name) {
+        return Ok(*canteen_id);
+    }
+    // drop here, otherwise drop would occur after the write lock (≙ dead lock)
+    drop(canteen_map_inv_r);
+    let extr_id = site_id.context("Failed to extract canteen id")?;
+
+    if CANTEEN_MAP_INV
+        .write()
+        .unwrap()
+        .insert(canteen_name.to_string(), extr_id)
+        // race conditions between writers and readers can cause two readers to think
+        // the value needs to be added (only writes lock exclusively)
+        .is_none()
+    {
+        log::info!("Adding new canteen to db: {}", canteen_name);
+        add_canteen_id_db(extr_id, canteen_name)?;
+        // no receivers is fine
+        let _ = CANTEEN_EVENTS.send(CanteenEvent::Added {
+            canteen: Canteen {
+                id: extr_id,
+                name: canteen_name.to_string(),
+            },
+        });
+    };
+
+    Ok(extr_id)
+}
+
 fn extract_canteenid(document: &Html, canteen_title: &str) -> Result<u32> {
     lazy_static! {
         static ref CANTEEN_LIST_SEL: Selector = Selector::parse("#locations>li").unwrap();
@@ -486,6 +679,19 @@ fn extract_canteenid(document: &Html, canteen_title: &str) -> Result<u32> {
     Err(anyhow!("Failed to extract canteen id"))
 }
 
+// CANTEEN_MAP holds the current names, CANTEEN_MAP_INV additionally maps the aliases
+// (previous names, names of merged canteens) so the scraper keeps recognizing them
+pub async fn load_canteen_maps() -> ApiResult<()> {
+    let canteens = get_canteens_from_db().await?;
+    // a current name wins over the same name being another canteen's alias
+    let mut canteen_map_inv = get_canteen_aliases_db()?;
+    canteen_map_inv.extend(invert_map(&canteens));
+
+    *CANTEEN_MAP_INV.write().unwrap() = canteen_map_inv;
+    *CANTEEN_MAP.write().unwrap() = canteens;
+    Ok(())
+}
+
 pub fn invert_map<K, V>(map: &BTreeMap<K, V>) -> BTreeMap<V, K>
 where
     K: Clone + Ord,
@@ -493,3 +699,286 @@ where
 {
     map.iter().map(|(k, v)| (v.clone(), k.clone())).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ChangeKind;
+
+    fn meal(name: &str, price: &str, ingredients: &[&str]) -> SingleMeal {
+        SingleMeal {
+            name: name.to_string(),
+            additional_ingredients: ingredients.iter().map(|i| i.to_string()).collect(),
+            allergens: None,
+            variations: None,
+            price: price.to_string(),
+        }
+    }
+
+    #[test]
+    fn typo_fix_is_matched() {
+        let old = meal("Gemüsecurry mit Reis", "2,90 €", &["vegan"]);
+        let new = meal("Gemüsecurry mit Reiß", "2,90 €", &["vegan"]);
+
+        let pairs = match_similar_meals(&[("Vegan", &new)], &[("Vegan", &old)]);
+        assert_eq!(pairs, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn moved_meal_is_matched_across_categories() {
+        let old = meal("Schnitzel mit Pommes", "3,80 €", &[]);
+        let new = meal("Schnitzel mit Pommes", "3,80 €", &[]);
+        let other = meal("Linsensuppe", "1,90 €", &[]);
+
+        let pairs = match_similar_meals(
+            &[("Aktionsgericht", &new)],
+            &[("Suppe", &other), ("Fleischgericht", &old)],
+        );
+        assert_eq!(pairs, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn same_category_is_preferred() {
+        let old_a = meal("Pasta Arrabiata", "2,50 €", &[]);
+        let old_b = meal("Pasta Arrabiata", "2,50 €", &[]);
+        let new = meal("Pasta Arrabiata", "2,50 €", &[]);
+
+        let pairs = match_similar_meals(
+            &[("Pasta", &new)],
+            &[("Vegetarisch", &old_a), ("Pasta", &old_b)],
+        );
+        assert_eq!(pairs, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn different_dish_at_same_price_is_not_matched() {
+        let old = meal("Kartoffelsuppe", "2,90 €", &[]);
+        let new = meal("Kartoffelpuffer", "2,90 €", &[]);
+
+        let pairs = match_similar_meals(&[("Vegetarisch", &new)], &[("Vegetarisch", &old)]);
+        assert!(pairs.is_empty());
+    }
+
+    #[test]
+    fn different_dish_with_same_details_is_not_matched() {
+        let old = meal("Hähnchenbrust mit Reis", "3,80 €", &["Reis"]);
+        let new = meal("Chili sin Carne", "3,80 €", &["Reis"]);
+
+        let pairs = match_similar_meals(&[("Tagesgericht", &new)], &[("Tagesgericht", &old)]);
+        assert!(pairs.is_empty());
+    }
+
+    fn strings(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn equal_lists_have_no_change() {
+        assert!(
+            ListChange::between(strings(&["Reis", "vegan"]), strings(&["Reis", "vegan"])).is_none()
+        );
+    }
+
+    #[test]
+    fn list_change_has_added_and_removed_entries() {
+        let change =
+            ListChange::between(strings(&["Reis", "vegan"]), strings(&["vegan", "Salat"])).unwrap();
+        assert_eq!(change.added, strings(&["Salat"]));
+        assert_eq!(change.removed, strings(&["Reis"]));
+        assert_eq!(change.old, strings(&["Reis", "vegan"]));
+        assert_eq!(change.new, strings(&["vegan", "Salat"]));
+    }
+
+    #[test]
+    fn reordered_list_has_nothing_added_or_removed() {
+        let change =
+            ListChange::between(strings(&["Reis", "vegan"]), strings(&["vegan", "Reis"])).unwrap();
+        assert!(change.added.is_empty());
+        assert!(change.removed.is_empty());
+    }
+
+    #[test]
+    fn price_change() {
+        let old = meal("Gemüsecurry", "2,90 €", &["vegan"]);
+        let new = meal("Gemüsecurry", "3,20 €", &["vegan"]);
+
+        let change = diff_single_meal("Vegan", "Vegan", &old, &new);
+        let price = change.price.as_ref().unwrap();
+        assert_eq!(
+            (price.old.as_str(), price.new.as_str()),
+            ("2,90 €", "3,20 €")
+        );
+        assert!(change.name.is_none() && change.category.is_none());
+        assert!(change.ingredients.is_none() && change.allergens.is_none());
+        assert!(change.variations.is_none());
+        assert_eq!(change.kind(), ChangeKind::Modified);
+        assert_eq!(change.summary(), "Gemüsecurry: price 2,90 € → 3,20 €");
+    }
+
+    #[test]
+    fn allergen_change_lists_labels() {
+        let old = SingleMeal {
+            allergens: Some("Allergene: Sellerie (I), Senf (J)".to_string()),
+            ..meal("Gemüsecurry", "2,90 €", &[])
+        };
+        let new = SingleMeal {
+            allergens: Some("Allergene: Sellerie (I), Gluten (A)".to_string()),
+            ..meal("Gemüsecurry", "2,90 €", &[])
+        };
+
+        let change = diff_single_meal("Vegan", "Vegan", &old, &new);
+        let allergens = change.allergens.as_ref().unwrap();
+        assert_eq!(allergens.added, strings(&["Gluten (A)"]));
+        assert_eq!(allergens.removed, strings(&["Senf (J)"]));
+        assert!(change.price.is_none());
+        assert_eq!(
+            change.summary(),
+            "Gemüsecurry: allergens +Gluten (A), -Senf (J)"
+        );
+    }
+
+    #[test]
+    fn ingredient_change() {
+        let old = meal("Schnitzel", "3,80 €", &["Pommes"]);
+        let new = meal("Schnitzel", "3,80 €", &["Pommes", "Salat"]);
+
+        let change = diff_single_meal("Fleisch", "Fleisch", &old, &new);
+        let ingredients = change.ingredients.as_ref().unwrap();
+        assert_eq!(ingredients.added, strings(&["Salat"]));
+        assert!(ingredients.removed.is_empty());
+        assert_eq!(change.summary(), "Schnitzel: ingredients +Salat");
+    }
+
+    #[test]
+    fn reordered_ingredients_are_reported_as_such() {
+        let old = meal("Schnitzel", "3,80 €", &["Pommes", "Salat"]);
+        let new = meal("Schnitzel", "3,80 €", &["Salat", "Pommes"]);
+
+        let change = diff_single_meal("Fleisch", "Fleisch", &old, &new);
+        assert!(change.ingredients.is_some());
+        assert_eq!(change.summary(), "Schnitzel: ingredients reordered");
+    }
+
+    #[test]
+    fn variation_change() {
+        let old = meal("Schnitzel", "3,80 €", &[]);
+        let new = SingleMeal {
+            variations: Some(vec![MealVariation {
+                name: "Salat".to_string(),
+                allergens_and_add: Some("A, C".to_string()),
+            }]),
+            ..meal("Schnitzel", "3,80 €", &[])
+        };
+
+        let change = diff_single_meal("Fleisch", "Fleisch", &old, &new);
+        let variations = change.variations.as_ref().unwrap();
+        assert!(variations.old.is_empty());
+        assert_eq!(variations.new, new.variations.clone().unwrap());
+        assert_eq!(change.summary(), "Schnitzel: variations changed");
+    }
+
+    #[test]
+    fn rename_and_move_come_first_in_the_summary() {
+        let old = meal("Gemüsecurry mit Reis", "2,90 €", &[]);
+        let new = meal("Gemüsecurry mit Reiß", "3,20 €", &[]);
+
+        let renamed = diff_single_meal("Vegan", "Vegan", &old, &new);
+        assert_eq!(renamed.kind(), ChangeKind::Renamed);
+        assert_eq!(
+            renamed.summary(),
+            "Gemüsecurry mit Reiß: renamed from Gemüsecurry mit Reis, price 2,90 € → 3,20 €"
+        );
+
+        let moved = diff_single_meal("Vegan", "Aktion", &old, &new);
+        assert_eq!(moved.kind(), ChangeKind::Moved);
+        assert_eq!(moved.meal_type, "Aktion");
+        assert!(moved
+            .summary()
+            .starts_with("Gemüsecurry mit Reiß: moved from Vegan, renamed from"));
+    }
+
+    fn day(meal_groups: &[(&str, Vec<SingleMeal>)]) -> CanteenMealsDay {
+        CanteenMealsDay {
+            canteen_id: 106,
+            meal_groups: meal_groups
+                .iter()
+                .map(|(meal_type, sub_meals)| MealGroup {
+                    meal_type: meal_type.to_string(),
+                    sub_meals: sub_meals.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn meal_names(meal_groups: &Option<Vec<MealGroup>>) -> Vec<(String, String)> {
+        meal_groups
+            .iter()
+            .flatten()
+            .flat_map(|group| {
+                group
+                    .sub_meals
+                    .iter()
+                    .map(|meal| (group.meal_type.clone(), meal.name.clone()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renamed_and_moved_meals_are_neither_new_nor_removed() {
+        let old = day(&[
+            (
+                "Vegan",
+                vec![meal("Gemüsecurry mit Reis", "2,90 €", &["vegan"])],
+            ),
+            (
+                "Fleischgericht",
+                vec![meal("Schnitzel mit Pommes", "3,80 €", &[])],
+            ),
+            ("Suppe", vec![meal("Linsensuppe", "1,90 €", &[])]),
+        ]);
+        let new = day(&[
+            (
+                "Vegan",
+                vec![meal("Gemüsecurry mit Reiß", "2,90 €", &["vegan"])],
+            ),
+            (
+                "Aktionsgericht",
+                vec![meal("Schnitzel mit Pommes", "3,80 €", &[])],
+            ),
+            ("Suppe", vec![meal("Linsensuppe", "1,90 €", &[])]),
+        ]);
+
+        let diff = diff_canteen_meals("2026-10-19", Some(&old), &new);
+        assert_eq!(
+            meal_names(&diff.renamed_meals),
+            vec![("Vegan".to_string(), "Gemüsecurry mit Reiß".to_string())]
+        );
+        assert_eq!(
+            meal_names(&diff.moved_meals),
+            vec![(
+                "Aktionsgericht".to_string(),
+                "Schnitzel mit Pommes".to_string()
+            )]
+        );
+        assert!(diff.new_meals.is_none());
+        assert!(diff.removed_meals.is_none());
+        assert!(diff.modified_meals.is_none());
+        assert_eq!(
+            diff.change_kinds(),
+            vec![ChangeKind::Renamed, ChangeKind::Moved]
+        );
+
+        let meal_changes = diff.meal_changes.unwrap();
+        let renamed = meal_changes
+            .iter()
+            .find(|change| change.kind() == ChangeKind::Renamed)
+            .unwrap();
+        assert_eq!(renamed.name.as_ref().unwrap().old, "Gemüsecurry mit Reis");
+        let moved = meal_changes
+            .iter()
+            .find(|change| change.kind() == ChangeKind::Moved)
+            .unwrap();
+        assert_eq!(moved.category.as_ref().unwrap().old, "Fleischgericht");
+        assert!(moved.name.is_none());
+    }
+}
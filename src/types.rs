@@ -1,11 +1,13 @@
-use axum::{
-    body::Body,
-    response::{IntoResponse, Response},
-};
-use http::StatusCode;
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{
+    date_funcs::canteen_today, json_patch::PatchOperation, stuwe_request_funcs::build_date_string,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+#[graphql(complex)]
 pub struct Canteen {
     pub id: u32,
     pub name: String,
@@ -17,13 +19,343 @@ pub struct CanteenMealsDay {
     pub meal_groups: Vec<MealGroup>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// outcome of scraping a single date, kept in memory only
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrapeStatus {
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    // number of canteens found in the last successful scrape
+    pub canteens_seen: usize,
+}
+
+// a meals row as stored in the DB
+#[derive(Debug)]
+pub struct StoredDay {
+    pub date: String,
+    pub json_text: String,
+    pub last_changed: Option<DateTime<Utc>>,
+    // CanteenMealDiff JSON relative to the previously stored version
+    pub last_diff: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum DayStatus {
+    // canteen has meals on this day
+    Open,
+    // day was scraped, but canteen has no meals
+    Closed,
+    // nothing is stored for this day (e.g. weekend or too far ahead)
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CanteenDay {
+    pub status: DayStatus,
+    pub meal_groups: Vec<MealGroup>,
+    #[serde(skip)]
+    pub last_changed: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DateRangeQuery {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CanteenFilterQuery {
+    // comma separated list of canteen IDs, e.g. "106,111"
+    pub canteens: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum Diet {
+    Vegan,
+    Vegetarian,
+}
+
+#[derive(Deserialize, Debug, Default, InputObject)]
+#[graphql(name = "MealFilter")]
+pub struct MealFilterQuery {
+    // comma separated allergen codes or names, e.g. "A,Sellerie"
+    pub exclude_allergens: Option<String>,
+    pub diet: Option<Diet>,
+    // maximum student price in €, e.g. "3.50" or "3,50"
+    pub max_price: Option<String>,
+    // comma separated meal types
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum IcalMode {
+    // one event per day listing all meals
+    Day,
+    // one event per meal
+    Meal,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IcalQuery {
+    pub mode: Option<IcalMode>,
+}
+
+// representation of meal endpoints, chosen via ?format= or the Accept header
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Json,
+    Html,
+    #[serde(alias = "md")]
+    Markdown,
+    #[serde(alias = "txt")]
+    Text,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FormatQuery {
+    pub format: Option<ResponseFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
 pub struct CanteenMealDiff {
+    // position in the event log, 0 for diffs that were never broadcast
+    #[serde(default)]
+    pub seq: u64,
     pub canteen_id: u32,
+    // %Y-%m-%d, empty for diffs stored before dates were added
+    #[serde(default)]
+    pub date: String,
     pub new_meals: Option<Vec<MealGroup>>,
     pub modified_meals: Option<Vec<MealGroup>>,
     pub modified_meals_ignoring_allergens: Option<Vec<MealGroup>>,
     pub removed_meals: Option<Vec<MealGroup>>,
+    // similar meals whose name changed within a category
+    #[serde(default)]
+    pub renamed_meals: Option<Vec<MealGroup>>,
+    // meals now listed in another category
+    #[serde(default)]
+    pub moved_meals: Option<Vec<MealGroup>>,
+    // old and new values of the fields that changed in modified, renamed and moved meals
+    #[serde(default)]
+    pub meal_changes: Option<Vec<MealChange>>,
+    // stored revision of the canteen day after this change, 0 for diffs stored before revisions
+    #[serde(default)]
+    pub revision: u32,
+    // JSON Patch from the previous revision, only kept in memory for patch subscribers
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub patch: Vec<PatchOperation>,
+}
+
+// field-level changes of a modified meal, unchanged fields are None
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct MealChange {
+    pub meal_type: String,
+    // name after the change
+    pub meal: String,
+    // meal_type, for moved meals
+    pub category: Option<ValueChange>,
+    pub name: Option<ValueChange>,
+    pub price: Option<ValueChange>,
+    pub ingredients: Option<ListChange>,
+    // allergens as listed, e.g. "Senf (J)"
+    pub allergens: Option<ListChange>,
+    pub variations: Option<VariationsChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct ValueChange {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct ListChange {
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct VariationsChange {
+    pub old: Vec<MealVariation>,
+    pub new: Vec<MealVariation>,
+}
+
+impl ListChange {
+    // None if both lists are equal, reordered lists have nothing added or removed
+    pub fn between(old: Vec<String>, new: Vec<String>) -> Option<Self> {
+        if old == new {
+            return None;
+        }
+        let added = new
+            .iter()
+            .filter(|entry| !old.contains(entry))
+            .cloned()
+            .collect();
+        let removed = old
+            .iter()
+            .filter(|entry| !new.contains(entry))
+            .cloned()
+            .collect();
+        Some(ListChange {
+            old,
+            new,
+            added,
+            removed,
+        })
+    }
+}
+
+impl MealChange {
+    pub fn kind(&self) -> ChangeKind {
+        if self.category.is_some() {
+            ChangeKind::Moved
+        } else if self.name.is_some() {
+            ChangeKind::Renamed
+        } else {
+            ChangeKind::Modified
+        }
+    }
+
+    // e.g. "Gemüsecurry: price 3,20 € → 3,50 €, allergens +Senf (J)"
+    pub fn summary(&self) -> String {
+        let mut parts = vec![];
+        if let Some(category) = &self.category {
+            parts.push(format!("moved from {}", category.old));
+        }
+        if let Some(name) = &self.name {
+            parts.push(format!("renamed from {}", name.old));
+        }
+        if let Some(price) = &self.price {
+            parts.push(format!("price {} → {}", price.old, price.new));
+        }
+        for (label, list) in [
+            ("ingredients", &self.ingredients),
+            ("allergens", &self.allergens),
+        ] {
+            if let Some(list) = list {
+                if list.added.is_empty() && list.removed.is_empty() {
+                    parts.push(format!("{} reordered", label));
+                    continue;
+                }
+                let entries = list
+                    .added
+                    .iter()
+                    .map(|entry| format!("+{}", entry))
+                    .chain(list.removed.iter().map(|entry| format!("-{}", entry)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                parts.push(format!("{} {}", label, entries));
+            }
+        }
+        if self.variations.is_some() {
+            parts.push("variations changed".to_string());
+        }
+        if parts.is_empty() {
+            parts.push("changed".to_string());
+        }
+
+        format!("{}: {}", self.meal, parts.join(", "))
+    }
+}
+
+// which diffs a subscriber receives: only changes of today (the default of the
+// original endpoints) or changes of every upcoming day
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateScope {
+    #[default]
+    Today,
+    Upcoming,
+}
+
+impl UpdateScope {
+    pub fn includes(&self, diff: &CanteenMealDiff) -> bool {
+        let today = build_date_string(canteen_today());
+        match self {
+            UpdateScope::Today => diff.date == today,
+            // replayed diffs can be of days that have passed since. the ISO dates sort by day
+            UpdateScope::Upcoming => diff.date >= today,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsQuery {
+    pub scope: Option<UpdateScope>,
+    // sequence number of the last received diff, newer diffs are replayed on connect
+    pub since: Option<u64>,
+    // starts with a snapshot of the stored meals instead (diff WebSocket only)
+    #[serde(default)]
+    pub snapshot: bool,
+    // patch: JSON Patches instead of diffs (diff WebSocket only)
+    #[serde(default)]
+    pub format: DiffFormat,
+}
+
+// how changes are delivered to subscribers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffFormat {
+    #[default]
+    Diff,
+    // RFC 6902 operations on the stored meal groups of the day
+    Patch,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PatchQuery {
+    // revision the patch applies to, 0 (no meals) if missing
+    pub from: Option<u32>,
+    // revision the patch leads to, the latest if missing
+    pub to: Option<u32>,
+}
+
+// JSON Patch turning the meal groups of a canteen day from one revision into another
+#[derive(Serialize, Debug, Clone)]
+pub struct MealPatch {
+    // position in the event log, only set for broadcast patches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub canteen_id: u32,
+    pub date: String,
+    pub from_revision: u32,
+    pub revision: u32,
+    pub patch: Vec<PatchOperation>,
+}
+
+impl From<&CanteenMealDiff> for MealPatch {
+    fn from(diff: &CanteenMealDiff) -> Self {
+        MealPatch {
+            seq: Some(diff.seq),
+            canteen_id: diff.canteen_id,
+            date: diff.date.clone(),
+            from_revision: diff.revision.saturating_sub(1),
+            revision: diff.revision,
+            patch: diff.patch.clone(),
+        }
+    }
+}
+
+// stored meals of a canteen day, part of a snapshot
+#[derive(Serialize, Debug)]
+pub struct SnapshotDay {
+    pub canteen_id: u32,
+    pub date: String,
+    pub meal_groups: Vec<MealGroup>,
+}
+
+// consistent state of the stored meals, every diff with a higher seq applies on top of it
+#[derive(Serialize, Debug)]
+pub struct MealsSnapshot {
+    pub seq: u64,
+    pub days: Vec<SnapshotDay>,
 }
 
 pub trait HasChanges {
@@ -32,17 +364,109 @@ pub trait HasChanges {
 
 impl HasChanges for CanteenMealDiff {
     fn has_changes(&self) -> bool {
-        self.new_meals.is_some() || self.modified_meals.is_some() || self.removed_meals.is_some()
+        self.new_meals.is_some()
+            || self.modified_meals.is_some()
+            || self.removed_meals.is_some()
+            || self.renamed_meals.is_some()
+            || self.moved_meals.is_some()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+// changes of the canteen list, broadcasted via CANTEEN_EVENTS
+#[derive(Debug, Clone)]
+pub enum CanteenEvent {
+    // found by the scraper for the first time
+    Added { canteen: Canteen },
+    Renamed { canteen: Canteen },
+    // the canteen `id` no longer exists, its meals now belong to `into`
+    Merged { id: u32, into: u32 },
+}
+
+// kinds of changes a diff can contain, used by subscribers to filter notifications
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    New,
+    Modified,
+    Removed,
+    Renamed,
+    Moved,
+}
+
+impl CanteenMealDiff {
+    pub fn change_kinds(&self) -> Vec<ChangeKind> {
+        [
+            (self.new_meals.is_some(), ChangeKind::New),
+            (self.modified_meals.is_some(), ChangeKind::Modified),
+            (self.removed_meals.is_some(), ChangeKind::Removed),
+            (self.renamed_meals.is_some(), ChangeKind::Renamed),
+            (self.moved_meals.is_some(), ChangeKind::Moved),
+        ]
+        .into_iter()
+        .filter_map(|(present, kind)| present.then_some(kind))
+        .collect()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    // None means all canteens / all kinds
+    pub canteens: Option<Vec<u32>>,
+    pub kinds: Option<Vec<ChangeKind>>,
+    pub scope: UpdateScope,
+    pub enabled: bool,
+    // consecutive failed deliveries, reset on success
+    pub failure_count: u32,
+    pub created_at: DateTime<Utc>,
+    pub last_delivery_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub secret: String,
+}
+
+impl Webhook {
+    pub fn wants(&self, diff: &CanteenMealDiff) -> bool {
+        diff.has_changes()
+            && self
+                .canteens
+                .as_ref()
+                .is_none_or(|canteens| canteens.contains(&diff.canteen_id))
+            && self.scope.includes(diff)
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| diff.change_kinds().iter().any(|kind| kinds.contains(kind)))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewWebhook {
+    pub url: String,
+    pub canteens: Option<Vec<u32>>,
+    pub kinds: Option<Vec<ChangeKind>>,
+    // today only if not given
+    pub scope: Option<UpdateScope>,
+    // generated if not given
+    pub secret: Option<String>,
+}
+
+// the secret is only ever returned on creation
+#[derive(Serialize, Debug)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SimpleObject)]
 pub struct MealGroup {
     pub meal_type: String,
     pub sub_meals: Vec<SingleMeal>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SimpleObject)]
 pub struct SingleMeal {
     pub name: String,
     pub additional_ingredients: Vec<String>,
@@ -51,20 +475,185 @@ pub struct SingleMeal {
     pub price: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Allergen {
+    pub code: String,
+    pub name: Option<String>,
+}
+
+impl SingleMeal {
+    // parses e.g. "2,90 € / 4,90 € / 6,30 €" (student / employee / guest) into cents
+    pub fn prices_cents(&self) -> Vec<u32> {
+        parse_prices_cents(&self.price)
+    }
+
+    // parses e.g. "Allergene: Gluten (A), Weizen (A1)"
+    pub fn allergen_list(&self) -> Vec<Allergen> {
+        self.allergens
+            .as_deref()
+            .map(parse_allergens)
+            .unwrap_or_default()
+    }
+}
+
+impl Allergen {
+    // "Gluten (A)", or just the code if there's no name
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.code),
+            None => self.code.clone(),
+        }
+    }
+}
+
+impl MealVariation {
+    // variations only list codes, e.g. "A, C"
+    pub fn allergen_list(&self) -> Vec<Allergen> {
+        self.allergens_and_add
+            .as_deref()
+            .map(parse_allergens)
+            .unwrap_or_default()
+    }
+}
+
+pub fn parse_prices_cents(price: &str) -> Vec<u32> {
+    price
+        .split('/')
+        .filter_map(|single_price| {
+            let single_price = single_price.replace('€', "").replace(',', ".");
+            let euros = single_price.trim().parse::<f64>().ok()?;
+            Some((euros * 100.0).round() as u32)
+        })
+        .collect()
+}
+
+pub fn parse_allergens(allergens: &str) -> Vec<Allergen> {
+    let allergens = allergens
+        .split_once(':')
+        .map(|(_, list)| list)
+        .unwrap_or(allergens);
+
+    allergens
+        .split(',')
+        .map(str::trim)
+        .filter(|allergen| !allergen.is_empty())
+        .map(|allergen| match allergen.rsplit_once('(') {
+            // "Gluten (A)"
+            Some((name, code)) => Allergen {
+                code: code.trim_end_matches(')').trim().to_string(),
+                name: Some(name.trim().to_string()),
+            },
+            // "A"
+            None => Allergen {
+                code: allergen.to_string(),
+                name: None,
+            },
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, SimpleObject)]
 pub struct MealVariation {
     pub name: String,
     pub allergens_and_add: Option<String>,
 }
 
-// API Response type
-pub struct ResponseError {
-    pub message: String,
-    pub status_code: StatusCode,
+// v2 API types
+#[derive(Serialize, Debug)]
+pub struct V2Response<T: Serialize> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canteen_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub data: T,
 }
 
-impl IntoResponse for ResponseError {
-    fn into_response(self) -> Response<Body> {
-        (self.status_code, self.message).into_response()
+#[derive(Serialize, Debug)]
+pub struct V2Day {
+    pub canteen_id: u32,
+    pub date: String,
+    pub status: DayStatus,
+    // last successful scrape of this date, None if it wasn't scraped since startup
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub last_changed_at: Option<DateTime<Utc>>,
+    pub meal_groups: Vec<V2MealGroup>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct V2DaySummary {
+    pub date: String,
+    pub status: DayStatus,
+    pub last_changed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct V2MealGroup {
+    pub category: String,
+    pub meals: Vec<V2Meal>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct V2Meal {
+    pub name: String,
+    pub ingredients: Vec<String>,
+    pub prices: Option<V2Prices>,
+    // price as shown by StuWe
+    pub price_text: String,
+    pub allergens: Vec<Allergen>,
+    pub variations: Vec<V2Variation>,
+}
+
+// all prices in cents
+#[derive(Serialize, Debug)]
+pub struct V2Prices {
+    pub student: u32,
+    pub employee: Option<u32>,
+    pub guest: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct V2Variation {
+    pub name: String,
+    pub allergens: Vec<Allergen>,
+}
+
+impl From<MealGroup> for V2MealGroup {
+    fn from(meal_group: MealGroup) -> Self {
+        V2MealGroup {
+            category: meal_group.meal_type,
+            meals: meal_group.sub_meals.into_iter().map(V2Meal::from).collect(),
+        }
+    }
+}
+
+impl From<SingleMeal> for V2Meal {
+    fn from(meal: SingleMeal) -> Self {
+        let prices = meal.prices_cents();
+        let allergens = meal.allergen_list();
+
+        V2Meal {
+            prices: prices.first().map(|student| V2Prices {
+                student: *student,
+                employee: prices.get(1).copied(),
+                guest: prices.get(2).copied(),
+            }),
+            allergens,
+            variations: meal
+                .variations
+                .unwrap_or_default()
+                .into_iter()
+                .map(|variation| V2Variation {
+                    allergens: variation.allergen_list(),
+                    name: variation.name,
+                })
+                .collect(),
+            name: meal.name,
+            ingredients: meal.additional_ingredients,
+            price_text: meal.price,
+        }
     }
 }
@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::pin,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use http::{HeaderMap, StatusCode};
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client, Url,
+};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use sha2::Sha256;
+use tokio::{
+    net::lookup_host,
+    sync::{broadcast, mpsc, Semaphore},
+};
+use tokio_stream::StreamExt;
+
+use crate::{
+    admin::AdminTokens,
+    auth::{bearer_token, constant_time_eq},
+    db_operations::{
+        add_webhook_db, count_webhooks_db, delete_webhook_db, enable_webhook_db, get_webhook_db,
+        list_webhooks_db, record_webhook_failure_db, record_webhook_success_db,
+    },
+    error::{ApiError, ApiJson, ApiPath, ApiResult},
+    event_log::{self, LogCursor, LogEvent},
+    metrics::WEBHOOK_DELIVERIES,
+    rate_limit::env_or,
+    types::{CanteenMealDiff, CreatedWebhook, NewWebhook, Webhook},
+};
+
+pub const SIGNATURE_HEADER: &str = "x-mensa-signature";
+pub const EVENT_HEADER: &str = "x-mensa-event";
+pub const DELIVERY_HEADER: &str = "x-mensa-delivery";
+
+const SECRET_LENGTH: usize = 32;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const LOAD_WEBHOOK_RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+];
+
+// hosts anyone may register webhooks for (comma separated WEBHOOK_ALLOWED_HOSTS), other hosts
+// need an admin token. these hosts may also resolve to internal addresses
+static ALLOWED_HOSTS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("WEBHOOK_ALLOWED_HOSTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|host| host.trim().to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+});
+
+pub async fn create_webhook(
+    headers: HeaderMap,
+    ApiJson(new_webhook): ApiJson<NewWebhook>,
+    admin_tokens: AdminTokens,
+) -> Result<Response, ApiError> {
+    let url = match Url::parse(&new_webhook.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => {
+            return Err(ApiError::InvalidRequest(
+                "url must be an absolute http(s) URL".to_string(),
+            ))
+        }
+    };
+    if !admin_tokens.authorize(&headers) && !url.host_str().is_some_and(is_allowed_host) {
+        return Err(ApiError::Unauthorized);
+    }
+    check_public_url(&url)
+        .await
+        .map_err(ApiError::InvalidRequest)?;
+    if new_webhook
+        .secret
+        .as_ref()
+        .is_some_and(|secret| secret.is_empty())
+    {
+        return Err(ApiError::InvalidRequest(
+            "secret must not be empty".to_string(),
+        ));
+    }
+
+    let max_webhooks = env_or("WEBHOOK_MAX_COUNT", 100);
+    if count_webhooks_db()? >= max_webhooks {
+        return Err(ApiError::InvalidRequest(format!(
+            "At most {} webhooks can be registered",
+            max_webhooks
+        )));
+    }
+
+    let secret = new_webhook.secret.clone().unwrap_or_else(generate_secret);
+    let webhook = add_webhook_db(&new_webhook, &secret)?;
+    log::info!("Webhook {} registered for {}", webhook.id, webhook.url);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedWebhook { webhook, secret }),
+    )
+        .into_response())
+}
+
+pub async fn get_webhook(
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    let webhook = authorized_webhook(&headers, id)?;
+    Ok(Json(webhook).into_response())
+}
+
+pub async fn delete_webhook(
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    authorized_webhook(&headers, id)?;
+    delete_webhook_db(id)?;
+    log::info!("Webhook {} deleted", id);
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// re-enables a webhook that was disabled after too many failed deliveries
+pub async fn enable_webhook(
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<u32>,
+) -> Result<Response, ApiError> {
+    authorized_webhook(&headers, id)?;
+    enable_webhook_db(id)?;
+
+    Ok(Json(get_webhook_db(id)?.ok_or(ApiError::WebhookNotFound)?).into_response())
+}
+
+// a webhook is managed with its own secret as bearer token.
+// unknown IDs and wrong secrets look the same, so IDs can't be probed
+fn authorized_webhook(headers: &HeaderMap, id: u32) -> Result<Webhook, ApiError> {
+    let token = bearer_token(headers).ok_or(ApiError::Unauthorized)?;
+
+    match get_webhook_db(id)? {
+        Some(webhook) if constant_time_eq(webhook.secret.as_bytes(), token.as_bytes()) => {
+            Ok(webhook)
+        }
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+// delivers every broadcast diff to the matching webhooks, configured via
+// WEBHOOK_MAX_RETRIES (per delivery), WEBHOOK_MAX_FAILURES (until a webhook is disabled) and
+// WEBHOOK_MAX_CONCURRENT_DELIVERIES
+pub fn start_webhook_dispatcher(today_updated_tx: &broadcast::Sender<CanteenMealDiff>) {
+    let rx = today_updated_tx.subscribe();
+    let max_retries = env_or("WEBHOOK_MAX_RETRIES", 3);
+    let max_failures = env_or("WEBHOOK_MAX_FAILURES", 5);
+    let deliveries = Arc::new(Semaphore::new(env_or(
+        "WEBHOOK_MAX_CONCURRENT_DELIVERIES",
+        8,
+    )));
+
+    // redirects could lead to internal addresses, so they count as failed deliveries
+    let reqwest_client = Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .unwrap();
+    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(max_retries);
+    let client = ClientBuilder::new(reqwest_client)
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .build();
+
+    tokio::spawn(async move {
+        // one worker per webhook, so its deliveries are sent in order and one at a time
+        let mut workers: HashMap<u32, mpsc::UnboundedSender<Delivery>> = HashMap::new();
+
+        let mut events = pin!(event_log::follow(rx, LogCursor::new(None), "webhooks"));
+        while let Some(event) = events.next().await {
+            let diff = match event {
+                LogEvent::Diff(diff) => *diff,
+                LogEvent::Lost { after_seq } => {
+                    log::warn!("Webhook dispatcher lost the diffs after {}", after_seq);
+                    continue;
+                }
+            };
+
+            let webhooks = match list_webhooks_db(true) {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    log::error!("Loading webhooks failed: {}", e);
+                    continue;
+                }
+            };
+            // workers of deleted or disabled webhooks stop once their queue is dropped
+            workers.retain(|id, _| webhooks.iter().any(|webhook| webhook.id == *id));
+            let body = serde_json::to_vec(&diff).unwrap();
+
+            for webhook in webhooks.into_iter().filter(|webhook| webhook.wants(&diff)) {
+                let delivery = Delivery {
+                    body: body.clone(),
+                    seq: diff.seq,
+                };
+                let delivery = match workers.get(&webhook.id) {
+                    Some(worker) => match worker.send(delivery) {
+                        Ok(()) => continue,
+                        Err(mpsc::error::SendError(delivery)) => delivery,
+                    },
+                    None => delivery,
+                };
+
+                let (worker, queue) = mpsc::unbounded_channel();
+                worker.send(delivery).unwrap();
+                workers.insert(webhook.id, worker);
+                tokio::spawn(webhook_worker(
+                    client.clone(),
+                    deliveries.clone(),
+                    webhook.id,
+                    queue,
+                    max_failures,
+                ));
+            }
+        }
+    });
+}
+
+struct Delivery {
+    body: Vec<u8>,
+    seq: u64,
+}
+
+// sends the queued diffs of a webhook. everything queued at once (e.g. all diffs of one scrape)
+// is a batch, which counts as a single failure, so an unreachable endpoint isn't disabled
+// by the diffs of one run alone
+async fn webhook_worker(
+    client: ClientWithMiddleware,
+    deliveries: Arc<Semaphore>,
+    id: u32,
+    mut queue: mpsc::UnboundedReceiver<Delivery>,
+    max_failures: u32,
+) {
+    while let Some(first) = queue.recv().await {
+        let mut batch = vec![first];
+        while let Ok(delivery) = queue.try_recv() {
+            batch.push(delivery);
+        }
+
+        // the webhook might have been deleted or disabled while the batch was queued
+        let webhook = match load_webhook(id).await {
+            Ok(Some(webhook)) if webhook.enabled => webhook,
+            Ok(_) => break,
+            Err(e) => {
+                log::error!(
+                    "Loading webhook {} failed, dropping {} deliveries: {}",
+                    id,
+                    batch.len(),
+                    e
+                );
+                WEBHOOK_DELIVERIES
+                    .with_label_values(&["failure"])
+                    .inc_by(batch.len() as u64);
+                continue;
+            }
+        };
+
+        let mut error = None;
+        for delivery in batch {
+            let _permit = deliveries.acquire().await.unwrap();
+            if let Err(e) = deliver(&client, &webhook, delivery.body, delivery.seq).await {
+                error = Some(e);
+            }
+        }
+
+        let recorded = match error {
+            None => record_webhook_success_db(id),
+            Some(error) => {
+                record_webhook_failure_db(id, &error, max_failures).map(|still_enabled| {
+                    if !still_enabled {
+                        log::warn!(
+                            "Webhook {} disabled after {} failed deliveries",
+                            id,
+                            max_failures
+                        );
+                    }
+                })
+            }
+        };
+        if let Err(e) = recorded {
+            log::error!("Saving delivery of webhook {} failed: {}", id, e);
+        }
+    }
+}
+
+// retried after a delay, so a briefly unavailable DB doesn't drop the batch
+async fn load_webhook(id: u32) -> ApiResult<Option<Webhook>> {
+    let mut delays = LOAD_WEBHOOK_RETRY_DELAYS.iter();
+    loop {
+        match get_webhook_db(id) {
+            Err(e) => match delays.next() {
+                Some(delay) => {
+                    log::warn!(
+                        "Loading webhook {} failed, retrying in {:?}: {}",
+                        id,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(*delay).await;
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+async fn deliver(
+    client: &ClientWithMiddleware,
+    webhook: &Webhook,
+    body: Vec<u8>,
+    seq: u64,
+) -> Result<(), String> {
+    let checked_url = match Url::parse(&webhook.url) {
+        Ok(url) => check_public_url(&url).await,
+        Err(e) => Err(e.to_string()),
+    };
+    let result = match checked_url {
+        Ok(()) => send_delivery(client, webhook, body, seq).await,
+        Err(error) => Err(error),
+    };
+
+    match &result {
+        Ok(()) => WEBHOOK_DELIVERIES.with_label_values(&["success"]).inc(),
+        Err(error) => {
+            WEBHOOK_DELIVERIES.with_label_values(&["failure"]).inc();
+            log::warn!("Webhook {} delivery failed: {}", webhook.id, error);
+        }
+    }
+    result
+}
+
+async fn send_delivery(
+    client: &ClientWithMiddleware,
+    webhook: &Webhook,
+    body: Vec<u8>,
+    seq: u64,
+) -> Result<(), String> {
+    client
+        .post(&webhook.url)
+        .header("content-type", "application/json")
+        .header(SIGNATURE_HEADER, sign(&webhook.secret, &body))
+        .header(EVENT_HEADER, "meals_changed")
+        .header(DELIVERY_HEADER, seq.to_string())
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|response| {
+            response
+                .error_for_status()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+}
+
+fn is_allowed_host(host: &str) -> bool {
+    ALLOWED_HOSTS.contains(&host.to_lowercase())
+}
+
+// loopback, private, link-local (incl. cloud metadata services), shared and other non-global
+// addresses, which webhooks must not reach
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "this network" 0.0.0.0/8, shared address space 100.64.0.0/10
+                // and reserved 240.0.0.0/4 (incl. broadcast)
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64)
+                || first >= 240
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_internal_ip(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+// the IPv4 address an IPv6 address is translated or tunneled to: IPv4-mapped ::ffff:a.b.c.d,
+// IPv4-compatible ::a.b.c.d (also covers :: and ::1), NAT64 64:ff9b::/96 and 6to4 2002::/16
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0 | 0xffff, _, _] | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(
+            Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]),
+        ),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+// addresses of a webhook host, an error if any of them is internal (unless the host is allowed)
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|e| format!("{} can't be resolved: {}", host, e))?
+        .collect();
+    if !is_allowed_host(host) {
+        if let Some(addr) = addrs.iter().find(|addr| is_internal_ip(addr.ip())) {
+            return Err(format!(
+                "{} resolves to the internal address {}",
+                host,
+                addr.ip()
+            ));
+        }
+    }
+    Ok(addrs)
+}
+
+async fn check_public_url(url: &Url) -> Result<(), String> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| "url must contain a host".to_string())?;
+    // IPv6 addresses are bracketed in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    resolve_public(host, url.port_or_known_default().unwrap_or(80))
+        .await
+        .map(|_| ())
+}
+
+// used by the delivery client, so a host can't switch to an internal address after it was checked
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// "sha256=" + hex HMAC-SHA256 of the body, keyed with the webhook's secret
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+fn generate_secret() -> String {
+    StdRng::from_entropy()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn internal_ipv4_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.178.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        for ip in ["1.1.1.1", "141.30.1.1", "100.128.0.1", "223.1.1.1"] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn internal_ipv6_addresses() {
+        for ip in ["::1", "::", "fd00::1", "fe80::1", "ff02::1"] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        for ip in ["2606:4700::1111", "2001:638:900::1"] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked() {
+        for ip in [
+            // mapped, compatible, NAT64, 6to4
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "2002:c0a8:0101::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(internal(ip), "{} should be internal", ip);
+        }
+        for ip in ["::ffff:1.1.1.1", "64:ff9b::1.1.1.1", "2002:0101:0101::1"] {
+            assert!(!internal(ip), "{} should be public", ip);
+        }
+    }
+}
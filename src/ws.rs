@@ -0,0 +1,513 @@
+use std::{borrow::Cow, collections::BTreeMap, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{interval_at, Instant, Interval},
+};
+
+use crate::{
+    constants::CANTEEN_EVENTS,
+    date_funcs::resolve_date,
+    error::ApiError,
+    event_log::{self, LogCursor},
+    metrics::{WsClientGuard, BROADCAST_LAGGED},
+    snapshot::{snapshot_dates, take_snapshot},
+    stuwe_request_funcs::build_date_string,
+    types::{
+        Canteen, CanteenEvent, CanteenMealDiff, ChangeKind, DiffFormat, HasChanges, MealPatch,
+        MealsSnapshot, UpdateScope,
+    },
+};
+
+// bumped on incompatible changes of the messages below
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// pings (and heartbeat messages) are sent this often
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// clients that neither answered a ping nor sent anything else for this long are disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(75);
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+// pings clients regularly and notices dead connections
+pub struct Keepalive {
+    interval: Interval,
+    last_seen: Instant,
+}
+
+impl Keepalive {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Keepalive {
+            interval: interval_at(now + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL),
+            last_seen: now,
+        }
+    }
+
+    // any message from the client (including pongs) proves the connection is alive
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    // waits for the next ping, false if the client timed out instead
+    pub async fn tick(&mut self) -> bool {
+        self.interval.tick().await;
+        self.last_seen.elapsed() <= CLIENT_TIMEOUT
+    }
+}
+
+pub async fn ping(socket: &mut WebSocket) -> Result<(), axum::Error> {
+    socket.send(Message::Ping(Vec::new())).await
+}
+
+// close code (from the private range) for clients of the legacy endpoints whose diffs can't be
+// replayed anymore, they have to reconnect without `since` (e.g. with `snapshot=true`)
+pub const EVENTS_LOST_CLOSE_CODE: u16 = 4001;
+
+// ends the connection with a close frame, errors don't matter anymore at this point
+pub async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: Cow::Borrowed(reason),
+        })))
+        .await;
+}
+
+pub async fn close_timed_out(socket: WebSocket) {
+    log::info!("WebSocket client timed out");
+    close(socket, close_code::AWAY, "ping timeout").await;
+}
+
+// messages sent by clients
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // a missing filter matches everything
+    Subscribe {
+        canteens: Option<Vec<u32>>,
+        kinds: Option<Vec<ChangeKind>>,
+        // %Y-%m-%d or an alias like 'tomorrow', resolved when subscribing
+        dates: Option<Vec<String>>,
+        // today only by default, unless dates are given
+        scope: Option<UpdateScope>,
+        // replays the kept diffs after this sequence number that match the subscription
+        since: Option<u64>,
+        // sends the subscription's stored meals first, versioned by the diffs' seq
+        #[serde(default)]
+        snapshot: bool,
+        // patch: meals_patched messages with JSON Patches instead of meals_changed
+        #[serde(default)]
+        format: DiffFormat,
+    },
+    // without an ID all subscriptions are removed
+    Unsubscribe {
+        id: Option<u32>,
+    },
+}
+
+// messages sent by the server, every message has a `type`
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        protocol_version: u32,
+        heartbeat_interval_secs: u64,
+    },
+    Subscribed {
+        id: u32,
+        #[serde(flatten)]
+        subscription: Subscription,
+    },
+    Unsubscribed {
+        ids: Vec<u32>,
+    },
+    MealsChanged {
+        // IDs of the matching subscriptions
+        subscriptions: Vec<u32>,
+        date: String,
+        kinds: Vec<ChangeKind>,
+        diff: CanteenMealDiff,
+    },
+    // like meals_changed, for subscriptions with format patch
+    MealsPatched {
+        subscriptions: Vec<u32>,
+        kinds: Vec<ChangeKind>,
+        #[serde(flatten)]
+        patch: MealPatch,
+    },
+    CanteenAdded {
+        canteen: Canteen,
+    },
+    CanteenRenamed {
+        canteen: Canteen,
+    },
+    CanteenMerged {
+        id: u32,
+        into: u32,
+    },
+    // stored meals of the subscribed canteens and dates, diffs with a higher seq apply on top
+    Snapshot {
+        subscription: u32,
+        #[serde(flatten)]
+        snapshot: MealsSnapshot,
+    },
+    Heartbeat {
+        timestamp: DateTime<Utc>,
+    },
+    // some diffs after `after_seq` couldn't be delivered (the client lagged behind or
+    // resumed from a sequence number that isn't kept anymore)
+    EventsLost {
+        after_seq: u64,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl From<ApiError> for ServerMessage {
+    fn from(error: ApiError) -> Self {
+        ServerMessage::Error {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<CanteenEvent> for ServerMessage {
+    fn from(event: CanteenEvent) -> Self {
+        match event {
+            CanteenEvent::Added { canteen } => ServerMessage::CanteenAdded { canteen },
+            CanteenEvent::Renamed { canteen } => ServerMessage::CanteenRenamed { canteen },
+            CanteenEvent::Merged { id, into } => ServerMessage::CanteenMerged { id, into },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Subscription {
+    canteens: Option<Vec<u32>>,
+    kinds: Option<Vec<ChangeKind>>,
+    dates: Option<Vec<String>>,
+    scope: UpdateScope,
+    format: DiffFormat,
+}
+
+impl Subscription {
+    fn matches(&self, diff: &CanteenMealDiff, kinds: &[ChangeKind]) -> bool {
+        // revisions without meal changes only matter for following the patches
+        let wanted_kinds = if diff.has_changes() {
+            self.kinds
+                .as_ref()
+                .is_none_or(|wanted| kinds.iter().any(|kind| wanted.contains(kind)))
+        } else {
+            self.format == DiffFormat::Patch
+        };
+        self.scope.includes(diff)
+            && self
+                .canteens
+                .as_ref()
+                .is_none_or(|canteens| canteens.contains(&diff.canteen_id))
+            && wanted_kinds
+            && self
+                .dates
+                .as_ref()
+                .is_none_or(|dates| dates.contains(&diff.date))
+    }
+}
+
+// http → websocket with the typed protocol, clients only get the changes they subscribed to
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    today_updated_tx: broadcast::Sender<CanteenMealDiff>,
+) -> impl IntoResponse {
+    log::info!(
+        "WebSocket client connected (protocol v{})",
+        PROTOCOL_VERSION
+    );
+    ws.on_upgrade(|socket| websocket_protocol(socket, today_updated_tx))
+}
+
+async fn websocket_protocol(
+    mut socket: WebSocket,
+    today_updated_tx: broadcast::Sender<CanteenMealDiff>,
+) {
+    let mut diff_rx = today_updated_tx.subscribe();
+    let mut canteen_rx = CANTEEN_EVENTS.subscribe();
+    let _client_guard = WsClientGuard::new("ws");
+
+    let mut subscriptions = BTreeMap::new();
+    let mut next_id = 1;
+    let mut cursor = LogCursor::new(None);
+    let mut keepalive = Keepalive::start();
+
+    let hello = ServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        heartbeat_interval_secs: HEARTBEAT_INTERVAL.as_secs(),
+    };
+    if send(&mut socket, &hello).await.is_err() {
+        return;
+    }
+
+    loop {
+        let replies = tokio::select! {
+            msg = socket.recv() => {
+                keepalive.seen();
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        handle_client_message(&text, &mut subscriptions, &mut next_id, &mut cursor)
+                            .await
+                    }
+                    // pings are answered by axum, binary messages aren't part of the protocol.
+                    // the close frame is answered by the next read, which then ends the stream
+                    Some(Ok(
+                        Message::Binary(_) | Message::Ping(_) | Message::Pong(_) | Message::Close(_),
+                    )) => continue,
+                    // client has disconnected
+                    Some(Err(_)) | None => break,
+                }
+            },
+            diff = diff_rx.recv() => match diff {
+                Ok(diff) => match cursor.advance(diff) {
+                    Some(diff) => meals_changed(diff, &subscriptions),
+                    None => continue,
+                },
+                // slow clients get the missed diffs from the event log
+                Err(RecvError::Lagged(missed)) => {
+                    BROADCAST_LAGGED.with_label_values(&["ws"]).inc_by(missed);
+                    let after_seq = cursor.last_seq();
+                    let (diffs, complete) = cursor.catch_up();
+                    let lost = (!complete).then_some(ServerMessage::EventsLost { after_seq });
+                    lost.into_iter()
+                        .chain(diffs.into_iter().flat_map(|diff| meals_changed(diff, &subscriptions)))
+                        .collect()
+                }
+                Err(RecvError::Closed) => break,
+            },
+            event = canteen_rx.recv() => match event {
+                Ok(event) => vec![event.into()],
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            alive = keepalive.tick() => {
+                if !alive {
+                    close_timed_out(socket).await;
+                    return;
+                }
+                if ping(&mut socket).await.is_err() {
+                    break;
+                }
+                vec![ServerMessage::Heartbeat { timestamp: Utc::now() }]
+            },
+        };
+
+        for reply in replies {
+            if send(&mut socket, &reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_client_message(
+    text: &str,
+    subscriptions: &mut BTreeMap<u32, Subscription>,
+    next_id: &mut u32,
+    cursor: &mut LogCursor,
+) -> Vec<ServerMessage> {
+    let msg = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(msg) => msg,
+        Err(e) => {
+            return vec![ServerMessage::Error {
+                code: "invalid_message",
+                message: e.to_string(),
+            }]
+        }
+    };
+
+    match msg {
+        ClientMessage::Subscribe {
+            canteens,
+            kinds,
+            dates,
+            scope,
+            since,
+            snapshot,
+            format,
+        } => {
+            if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                return vec![ServerMessage::Error {
+                    code: "too_many_subscriptions",
+                    message: format!("At most {} subscriptions are allowed", MAX_SUBSCRIPTIONS),
+                }];
+            }
+            let dates = match dates
+                .map(|dates| {
+                    dates
+                        .iter()
+                        .map(|date| resolve_date(date, None).map(build_date_string))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()
+            {
+                Ok(dates) => dates,
+                Err(e) => return vec![e.into()],
+            };
+            if snapshot && since.is_some() {
+                return vec![ServerMessage::Error {
+                    code: "invalid_message",
+                    message: "since and snapshot can't be combined".to_string(),
+                }];
+            }
+
+            // explicitly requested dates must not be limited to today
+            let scope = scope.unwrap_or(if dates.is_some() {
+                UpdateScope::Upcoming
+            } else {
+                UpdateScope::Today
+            });
+            let subscription = Subscription {
+                canteens,
+                kinds,
+                dates,
+                scope,
+                format,
+            };
+
+            let mut replies = Vec::new();
+            let mut meals_snapshot = None;
+            if snapshot {
+                let taken = match snapshot_dates(scope, subscription.dates.as_deref()) {
+                    Ok(dates) => {
+                        take_snapshot(cursor, &dates, subscription.canteens.as_deref()).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match taken {
+                    // diffs the snapshot already contains still go to the earlier subscriptions
+                    Ok((taken, skipped)) => {
+                        replies.extend(
+                            skipped
+                                .into_iter()
+                                .flat_map(|diff| meals_changed(diff, subscriptions)),
+                        );
+                        meals_snapshot = Some(taken);
+                    }
+                    Err(e) => return vec![e.into()],
+                }
+            }
+
+            let id = *next_id;
+            *next_id += 1;
+            subscriptions.insert(id, subscription.clone());
+
+            replies.push(ServerMessage::Subscribed {
+                id,
+                subscription: subscription.clone(),
+            });
+            if let Some(snapshot) = meals_snapshot {
+                replies.push(ServerMessage::Snapshot {
+                    subscription: id,
+                    snapshot,
+                });
+            }
+            if let Some(since) = since {
+                replies.extend(replay_subscription(
+                    id,
+                    &subscription,
+                    since,
+                    cursor.last_seq(),
+                ));
+            }
+            replies
+        }
+        ClientMessage::Unsubscribe { id: Some(id) } => match subscriptions.remove(&id) {
+            Some(_) => vec![ServerMessage::Unsubscribed { ids: vec![id] }],
+            None => vec![ServerMessage::Error {
+                code: "subscription_not_found",
+                message: format!("No subscription with ID {}", id),
+            }],
+        },
+        ClientMessage::Unsubscribe { id: None } => {
+            let ids = subscriptions.keys().copied().collect();
+            subscriptions.clear();
+            vec![ServerMessage::Unsubscribed { ids }]
+        }
+    }
+}
+
+// kept diffs up to `until` the client missed before subscribing,
+// newer ones still arrive through the broadcast channel
+fn replay_subscription(
+    id: u32,
+    subscription: &Subscription,
+    since: u64,
+    until: u64,
+) -> Vec<ServerMessage> {
+    let (diffs, complete) = event_log::replay(since, until);
+    let lost = (!complete).then_some(ServerMessage::EventsLost { after_seq: since });
+
+    lost.into_iter()
+        .chain(diffs.into_iter().filter_map(|diff| {
+            let kinds = diff.change_kinds();
+            subscription
+                .matches(&diff, &kinds)
+                .then(|| changed_message(vec![id], subscription.format, kinds, diff))
+        }))
+        .collect()
+}
+
+// one message per format the matching subscriptions asked for
+fn meals_changed(
+    diff: CanteenMealDiff,
+    subscriptions: &BTreeMap<u32, Subscription>,
+) -> Vec<ServerMessage> {
+    let kinds = diff.change_kinds();
+    let mut matching: BTreeMap<DiffFormat, Vec<u32>> = BTreeMap::new();
+    for (id, subscription) in subscriptions {
+        if subscription.matches(&diff, &kinds) {
+            matching.entry(subscription.format).or_default().push(*id);
+        }
+    }
+
+    matching
+        .into_iter()
+        .map(|(format, ids)| changed_message(ids, format, kinds.clone(), diff.clone()))
+        .collect()
+}
+
+fn changed_message(
+    subscriptions: Vec<u32>,
+    format: DiffFormat,
+    kinds: Vec<ChangeKind>,
+    diff: CanteenMealDiff,
+) -> ServerMessage {
+    match format {
+        DiffFormat::Diff => ServerMessage::MealsChanged {
+            subscriptions,
+            date: diff.date.clone(),
+            kinds,
+            diff,
+        },
+        DiffFormat::Patch => ServerMessage::MealsPatched {
+            subscriptions,
+            kinds,
+            patch: MealPatch::from(&diff),
+        },
+    }
+}
+
+async fn send(socket: &mut WebSocket, msg: &ServerMessage) -> Result<(), axum::Error> {
+    socket
+        .send(Message::Text(serde_json::to_string(msg).unwrap()))
+        .await
+}